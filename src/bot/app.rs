@@ -1,5 +1,5 @@
 use crate::{
-    com,
+    com, endpoint,
     http::router::{self, HttpServer},
 };
 use log::*;
@@ -10,14 +10,20 @@ use std::{
         Arc,
     },
 };
-use tokio::{runtime::Builder, signal, sync::mpsc};
+use tokio::{runtime::Builder, signal, sync::watch, time};
 
 use super::{
+    channel,
     machine::{self, Liquidation},
     sub,
 };
-use anchor_client::solana_sdk::pubkey::Pubkey;
+use anchor_client::solana_sdk::commitment_config::CommitmentConfig;
 use std::net::ToSocketAddrs;
+use std::str::FromStr;
+
+// the time the workers and the burst transactions in flight are waited for on exit,
+// counted once the startup has stopped
+const SHUTDOWN_TIMEOUT: time::Duration = time::Duration::from_secs(60);
 
 pub fn run(ctx: com::Context, args: &clap::ArgMatches) -> anyhow::Result<()> {
     let tasks = match args.get_one::<usize>("tasks") {
@@ -32,6 +38,13 @@ pub fn run(ctx: com::Context, args: &clap::ArgMatches) -> anyhow::Result<()> {
         Some(i) => i.to_string(),
         None => "127.0.0.1".to_string(),
     };
+    let dry_run = args.get_flag("dry-run");
+    let commitment = match args.get_one::<String>("commitment") {
+        Some(c) => CommitmentConfig::from_str(c.as_str())
+            .map_err(|e| com::CliError::Unknown(e.to_string()))?,
+        None => CommitmentConfig::confirmed(),
+    };
+    let bot_ctx = Arc::new(com::BotContext::new(ctx.config, commitment)?);
     let address = format!("{}:{}", ip, port);
     let mut socket_addr: Option<SocketAddr> = None;
     if port > 0 {
@@ -60,41 +73,75 @@ pub fn run(ctx: com::Context, args: &clap::ArgMatches) -> anyhow::Result<()> {
         .map_err(|e| com::CliError::TokioRuntimeCreateField(e.to_string()))?;
     let mut sate_map = machine::StateMap::new(ctx.config.clone())?;
 
-    let (subscribe_tx, subscribe_rx) = mpsc::unbounded_channel::<Pubkey>();
-    sate_map.load_active_account_from_local(subscribe_tx.clone())?;
+    let (subscribe_tx, subscribe_rx) =
+        channel::unbounded::<sub::PriceSub>(&sate_map.channels, "price_sub");
+    sate_map.load_active_account_from_local()?;
 
     let config = ctx.config.clone();
+    let endpoints = bot_ctx.endpoints.clone();
+    let source = sub::new_account_source(&config, endpoints.clone())?;
     let mp = Arc::new(sate_map);
+    let storage = mp.storage.clone();
+    // stops the startup, e.g. the snapshot retries, when the exit signal comes first
+    let (startup_stop_tx, mut startup_stop_rx) = watch::channel(false);
     let task = runtime.spawn(async move {
-        let watch = machine::Watch::new(mp.clone(),subscribe_tx).await;
+        let monitor = endpoint::EndpointMonitor::new(endpoints.clone());
+        let watch = machine::Watch::new(config.clone(), mp.clone(), subscribe_tx.clone()).await;
         let sub = sub::SubAccount::new(
-            config.clone(),
-            watch.account_watch_tx.clone(),
-            watch.price_watch_tx.clone(),
-            subscribe_rx
+            source.as_ref(),
+            sub::SourceChannels {
+                account_watch_tx: watch.account_watch_tx.clone(),
+                price_watch_tx: watch.price_watch_tx.clone(),
+                subscribe_rx,
+                health: mp.sub_health.clone(),
+            },
         )
         .await;
-        // get all program accounts
-        match sub
-            .get_all_program_accounts(config.clone(), watch.account_watch_tx.clone())
-            .await
-        {
-            Ok(_) => {
-                info!("Complete the task of obtaining all account data!");
+        // subscribe the prices of the markets loaded from local, the subscriber is running now
+        mp.subscribe_price_accounts(&subscribe_tx);
+        // get all program accounts, liquidation stays paused until they are loaded,
+        // retry until they are or the exit signal comes
+        let mut backoff = sub::RECONNECT_MIN_BACKOFF;
+        loop {
+            let rs = tokio::select! {
+                rs = sub.get_all_program_accounts(&endpoints, watch.account_watch_tx.clone()) => rs,
+                _ = startup_stop_rx.changed() => {
+                    warn!("got exit signal before all program accounts were loaded.");
+                    break;
+                }
+            };
+            match rs {
+                Ok(_) => {
+                    info!("Complete the task of obtaining all account data!");
+                    mp.snapshot_ready.store(true, Ordering::Release);
+                    break;
+                }
+                Err(e) => {
+                    warn!(
+                        "Can not get all program accounts: {}, liquidation stays paused, retry in {:?}",
+                        e, backoff
+                    );
+                }
             }
-            Err(e) => {
-                error!("Can not get all program accounts: {}, This may result in a lack of account data.", e);
+            tokio::select! {
+                _ = time::sleep(backoff) => {}
+                _ = startup_stop_rx.changed() => {
+                    warn!("got exit signal before all program accounts were loaded.");
+                    break;
+                }
             }
+            backoff = (backoff * 2).min(sub::RECONNECT_MAX_BACKOFF);
         }
-        let liquidation = Liquidation::new(config.clone(), mp.clone(), tasks).await;
+        let liquidation =
+            Liquidation::new(config.clone(), bot_ctx, mp.clone(), tasks, dry_run).await;
         // start http server
         let web_server: Option<HttpServer> = match socket_addr {
             Some(addr) => Some(router::HttpServer::new(&addr, mp).await),
             None => None,
         };
-        (watch, sub, liquidation, web_server)
+        (watch, sub, liquidation, web_server, monitor)
     });
-    let s = runtime.block_on(async { signal::ctrl_c().await });
+    let s = runtime.block_on(shutdown_signal());
     match s {
         Ok(()) => {
             info!("got exit signal...start execution.")
@@ -103,18 +150,59 @@ pub fn run(ctx: com::Context, args: &clap::ArgMatches) -> anyhow::Result<()> {
             error!("Unable to listen for shutdown signal: {}", err);
         }
     }
+    let _ = startup_stop_tx.send(true);
     runtime.block_on(async {
-        let (wt, sb, lb, wb) = task.await.unwrap();
-        wt.shutdown().await;
-        sb.shutdown().await;
-        lb.shutdown().await;
-        match wb {
-            Some(s) => {
-                s.shutdown().await;
+        // the startup returns soon after the exit signal, e.g. between the snapshot retries
+        match task.await {
+            Ok((wt, sb, lb, wb, em)) => {
+                let shutdown = async {
+                    // stop sending transactions first, the subscriptions keep the state fresh meanwhile
+                    lb.shutdown().await;
+                    sb.shutdown().await;
+                    wt.shutdown().await;
+                    match wb {
+                        Some(s) => {
+                            s.shutdown().await;
+                        }
+                        None => {}
+                    }
+                    em.shutdown().await;
+                };
+                if time::timeout(SHUTDOWN_TIMEOUT, shutdown).await.is_err() {
+                    error!(
+                        "robot server did not shutdown in {:?}, drop the remaining tasks.",
+                        SHUTDOWN_TIMEOUT
+                    );
+                }
+            }
+            Err(e) => {
+                error!("robot server task error: {}", e);
+            }
+        }
+        match storage.flush().await {
+            Ok(n) => {
+                info!("flush {} bytes of local storage", n);
+            }
+            Err(e) => {
+                error!("flush local storage error: {}", e);
             }
-            None => {}
         }
         info!("robot server shutdown!");
     });
     Ok(())
 }
+
+// Wait for ctrl-c, or SIGTERM sent by the container runtime.
+#[cfg(unix)]
+async fn shutdown_signal() -> std::io::Result<()> {
+    let mut term = signal::unix::signal(signal::unix::SignalKind::terminate())?;
+    tokio::select! {
+        r = signal::ctrl_c() => r,
+        _ = term.recv() => Ok(()),
+    }
+}
+
+#[cfg(not(unix))]
+async fn shutdown_signal() -> std::io::Result<()> {
+    signal::ctrl_c().await
+}
@@ -0,0 +1,413 @@
+use super::sub::AccountUpdate;
+use anchor_client::solana_sdk::{account::Account, pubkey::Pubkey};
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{mpsc, Notify};
+
+// Counters of a channel.
+pub struct ChannelStats {
+    // 0 for the unbounded channels and the latest value channels, which hold one message
+    // per account
+    capacity: usize,
+    depth: AtomicUsize,
+    max_depth: AtomicUsize,
+    sent: AtomicU64,
+    // messages replaced by a newer one in the latest value channels
+    replaced: AtomicU64,
+    // messages rejected as older than the pending one in the latest value channels
+    stale: AtomicU64,
+}
+
+impl ChannelStats {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            depth: AtomicUsize::new(0),
+            max_depth: AtomicUsize::new(0),
+            sent: AtomicU64::new(0),
+            replaced: AtomicU64::new(0),
+            stale: AtomicU64::new(0),
+        }
+    }
+
+    // Count a message before it is sent, so the receiver never sees a negative depth.
+    fn on_send(&self) {
+        let depth = self.depth.fetch_add(1, Ordering::AcqRel) + 1;
+        self.max_depth.fetch_max(depth, Ordering::AcqRel);
+        self.sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn on_send_failed(&self) {
+        self.depth.fetch_sub(1, Ordering::AcqRel);
+        self.sent.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    fn on_recv(&self) {
+        self.depth.fetch_sub(1, Ordering::AcqRel);
+    }
+
+    fn on_replace(&self) {
+        self.replaced.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn on_stale(&self) {
+        self.stale.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth.load(Ordering::Acquire)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChannelSnapshot {
+    pub name: String,
+    pub capacity: usize,
+    pub depth: usize,
+    pub max_depth: usize,
+    pub sent: u64,
+    pub replaced: u64,
+    pub stale: u64,
+}
+
+// key is channel name
+pub type ChannelMetrics = Arc<DashMap<String, Arc<ChannelStats>>>;
+
+pub fn snapshot(metrics: &ChannelMetrics) -> Vec<ChannelSnapshot> {
+    let mut rs: Vec<ChannelSnapshot> = metrics
+        .iter()
+        .map(|v| ChannelSnapshot {
+            name: v.key().clone(),
+            capacity: v.capacity,
+            depth: v.depth(),
+            max_depth: v.max_depth.load(Ordering::Acquire),
+            sent: v.sent.load(Ordering::Relaxed),
+            replaced: v.replaced.load(Ordering::Relaxed),
+            stale: v.stale.load(Ordering::Relaxed),
+        })
+        .collect();
+    rs.sort_by(|a, b| a.name.cmp(&b.name));
+    rs
+}
+
+fn register(metrics: &ChannelMetrics, name: &str, capacity: usize) -> Arc<ChannelStats> {
+    let stats = Arc::new(ChannelStats::new(capacity));
+    metrics.insert(name.to_string(), stats.clone());
+    stats
+}
+
+// A bounded mpsc channel counting its depth, senders wait while it is full.
+pub fn bounded<T>(
+    metrics: &ChannelMetrics,
+    name: &str,
+    capacity: usize,
+) -> (Sender<T>, Receiver<T>) {
+    let (tx, rx) = mpsc::channel(capacity);
+    let stats = register(metrics, name, capacity);
+    (
+        Sender {
+            tx,
+            stats: stats.clone(),
+        },
+        Receiver { rx, stats },
+    )
+}
+
+pub struct Sender<T> {
+    tx: mpsc::Sender<T>,
+    stats: Arc<ChannelStats>,
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        Self {
+            tx: self.tx.clone(),
+            stats: self.stats.clone(),
+        }
+    }
+}
+
+impl<T> Sender<T> {
+    // Counted once there is room, so a send cancelled while it waits is not counted.
+    pub async fn send(&self, value: T) -> Result<(), mpsc::error::SendError<T>> {
+        match self.tx.reserve().await {
+            Ok(permit) => {
+                self.stats.on_send();
+                permit.send(value);
+                Ok(())
+            }
+            Err(_) => Err(mpsc::error::SendError(value)),
+        }
+    }
+}
+
+pub struct Receiver<T> {
+    rx: mpsc::Receiver<T>,
+    stats: Arc<ChannelStats>,
+}
+
+impl<T> Receiver<T> {
+    pub async fn recv(&mut self) -> Option<T> {
+        let rs = self.rx.recv().await;
+        if rs.is_some() {
+            self.stats.on_recv();
+        }
+        rs
+    }
+
+    // Receive a message if one is ready, without waiting for it.
+    pub async fn recv_ready(&mut self) -> Option<T> {
+        tokio::select! {
+            biased;
+            rs = self.recv() => rs,
+            _ = std::future::ready(()) => None,
+        }
+    }
+}
+
+// An unbounded mpsc channel counting its depth, for the control messages a sender must never
+// wait on, e.g. the price subscriptions of the watch, whose receiver also waits on the watch.
+pub fn unbounded<T>(
+    metrics: &ChannelMetrics,
+    name: &str,
+) -> (UnboundedSender<T>, UnboundedReceiver<T>) {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let stats = register(metrics, name, 0);
+    (
+        UnboundedSender {
+            tx,
+            stats: stats.clone(),
+        },
+        UnboundedReceiver { rx, stats },
+    )
+}
+
+pub struct UnboundedSender<T> {
+    tx: mpsc::UnboundedSender<T>,
+    stats: Arc<ChannelStats>,
+}
+
+impl<T> Clone for UnboundedSender<T> {
+    fn clone(&self) -> Self {
+        Self {
+            tx: self.tx.clone(),
+            stats: self.stats.clone(),
+        }
+    }
+}
+
+impl<T> UnboundedSender<T> {
+    pub fn send(&self, value: T) -> Result<(), mpsc::error::SendError<T>> {
+        self.stats.on_send();
+        let rs = self.tx.send(value);
+        if rs.is_err() {
+            self.stats.on_send_failed();
+        }
+        rs
+    }
+}
+
+pub struct UnboundedReceiver<T> {
+    rx: mpsc::UnboundedReceiver<T>,
+    stats: Arc<ChannelStats>,
+}
+
+impl<T> UnboundedReceiver<T> {
+    pub async fn recv(&mut self) -> Option<T> {
+        let rs = self.rx.recv().await;
+        if rs.is_some() {
+            self.stats.on_recv();
+        }
+        rs
+    }
+}
+
+struct Pending {
+    updates: HashMap<Pubkey, (Account, u64)>,
+    // accounts in the order of their first pending update
+    order: VecDeque<Pubkey>,
+}
+
+struct Latest {
+    pending: Mutex<Pending>,
+    notify: Notify,
+    stats: Arc<ChannelStats>,
+}
+
+// A channel of account updates that keeps only the latest update of each account, the
+// receiver always gets the freshest state and the depth is bounded by the number of accounts.
+pub fn latest(metrics: &ChannelMetrics, name: &str) -> (LatestSender, LatestReceiver) {
+    let inner = Arc::new(Latest {
+        pending: Mutex::new(Pending {
+            updates: HashMap::new(),
+            order: VecDeque::new(),
+        }),
+        notify: Notify::new(),
+        stats: register(metrics, name, 0),
+    });
+    (
+        LatestSender {
+            inner: inner.clone(),
+        },
+        LatestReceiver { inner },
+    )
+}
+
+#[derive(Clone)]
+pub struct LatestSender {
+    inner: Arc<Latest>,
+}
+
+impl LatestSender {
+    // Replace the pending update of the account unless it is from a newer slot.
+    pub fn send(&self, update: AccountUpdate) {
+        let (pubkey, account, slot) = update;
+        {
+            let mut pending = self.inner.pending.lock().unwrap();
+            match pending.updates.get_mut(&pubkey) {
+                Some(v) => {
+                    if v.1 > slot {
+                        self.inner.stats.on_stale();
+                        return;
+                    }
+                    self.inner.stats.on_replace();
+                    *v = (account, slot);
+                }
+                None => {
+                    self.inner.stats.on_send();
+                    pending.updates.insert(pubkey, (account, slot));
+                    pending.order.push_back(pubkey);
+                }
+            }
+        }
+        self.inner.notify.notify_one();
+    }
+}
+
+pub struct LatestReceiver {
+    inner: Arc<Latest>,
+}
+
+impl LatestReceiver {
+    // Wait for the next account with a pending update.
+    pub async fn recv(&mut self) -> AccountUpdate {
+        loop {
+            let update = {
+                let mut pending = self.inner.pending.lock().unwrap();
+                pending.order.pop_front().and_then(|pubkey| {
+                    pending
+                        .updates
+                        .remove(&pubkey)
+                        .map(|(account, slot)| (pubkey, account, slot))
+                })
+            };
+            if let Some(u) = update {
+                self.inner.stats.on_recv();
+                return u;
+            }
+            self.inner.notify.notified().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::{timeout, Duration};
+
+    fn metrics() -> ChannelMetrics {
+        Arc::new(DashMap::new())
+    }
+
+    fn stats(metrics: &ChannelMetrics, name: &str) -> ChannelSnapshot {
+        snapshot(metrics)
+            .into_iter()
+            .find(|v| v.name == name)
+            .unwrap()
+    }
+
+    fn update(pubkey: Pubkey, lamports: u64, slot: u64) -> AccountUpdate {
+        (
+            pubkey,
+            Account {
+                lamports,
+                ..Account::default()
+            },
+            slot,
+        )
+    }
+
+    #[tokio::test]
+    async fn bounded_counts_depth_until_received() {
+        let m = metrics();
+        let (tx, mut rx) = bounded::<u64>(&m, "b", 2);
+        tx.send(1).await.unwrap();
+        tx.send(2).await.unwrap();
+        let s = stats(&m, "b");
+        assert_eq!((s.capacity, s.depth, s.max_depth, s.sent), (2, 2, 2, 2));
+        // the sender waits while the channel is full
+        assert!(timeout(Duration::from_millis(20), tx.send(3))
+            .await
+            .is_err());
+        assert_eq!(rx.recv().await, Some(1));
+        assert_eq!(rx.recv().await, Some(2));
+        let s = stats(&m, "b");
+        assert_eq!((s.depth, s.max_depth), (0, 2));
+        drop(rx);
+        assert!(tx.send(4).await.is_err());
+        assert_eq!(stats(&m, "b").depth, 0);
+    }
+
+    #[tokio::test]
+    async fn recv_ready_does_not_wait() {
+        let m = metrics();
+        let (tx, mut rx) = bounded::<u64>(&m, "b", 4);
+        assert_eq!(rx.recv_ready().await, None);
+        tx.send(1).await.unwrap();
+        tx.send(2).await.unwrap();
+        assert_eq!(rx.recv_ready().await, Some(1));
+        assert_eq!(rx.recv_ready().await, Some(2));
+        assert_eq!(rx.recv_ready().await, None);
+        assert_eq!(stats(&m, "b").depth, 0);
+    }
+
+    #[tokio::test]
+    async fn unbounded_never_waits() {
+        let m = metrics();
+        let (tx, mut rx) = unbounded::<u64>(&m, "u");
+        for i in 0..1000 {
+            tx.send(i).unwrap();
+        }
+        assert_eq!(stats(&m, "u").depth, 1000);
+        assert_eq!(rx.recv().await, Some(0));
+        assert_eq!(stats(&m, "u").depth, 999);
+    }
+
+    #[tokio::test]
+    async fn latest_keeps_the_newest_update_of_each_account() {
+        let m = metrics();
+        let (tx, mut rx) = latest(&m, "l");
+        let (a, b) = (Pubkey::new_unique(), Pubkey::new_unique());
+        tx.send(update(a, 1, 10));
+        tx.send(update(b, 1, 10));
+        tx.send(update(a, 2, 12));
+        // older than the pending update of a
+        tx.send(update(a, 3, 11));
+        let s = stats(&m, "l");
+        assert_eq!((s.depth, s.sent, s.replaced, s.stale), (2, 2, 1, 1));
+        // in the order of the first pending update
+        let (k, account, slot) = rx.recv().await;
+        assert_eq!((k, account.lamports, slot), (a, 2, 12));
+        let (k, _, slot) = rx.recv().await;
+        assert_eq!((k, slot), (b, 10));
+        assert_eq!(stats(&m, "l").depth, 0);
+        // nothing pending, the receiver waits for the next send
+        assert!(timeout(Duration::from_millis(20), rx.recv()).await.is_err());
+        tx.send(update(b, 4, 13));
+        let (k, account, _) = timeout(Duration::from_secs(1), rx.recv()).await.unwrap();
+        assert_eq!((k, account.lamports), (b, 4));
+    }
+}
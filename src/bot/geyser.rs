@@ -0,0 +1,660 @@
+use super::sub::{self, AccountSource, Heartbeat, PriceSub, Reconnect, SourceChannels, SubExit};
+use crate::{com, config, endpoint};
+use anchor_client::solana_sdk::{account::Account, pubkey::Pubkey};
+use log::{debug, error, info, warn};
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use tokio::{
+    sync::{mpsc, watch},
+    task::JoinHandle,
+    time::{self, Duration},
+};
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tonic::codegen::http::uri::PathAndQuery;
+use tonic::metadata::AsciiMetadataValue;
+
+const SUBSCRIBE_PATH: &str = "/geyser.Geyser/Subscribe";
+const PROGRAM_FILTER: &str = "program";
+const PRICE_FILTER: &str = "price";
+const SLOT_FILTER: &str = "slot";
+// How often the stream checks its heartbeat.
+const HEARTBEAT_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+// A hand-copied subset of the messages of proto/geyser.proto of yellowstone-grpc, pinned to
+// the 1.x protocol (package geyser, service Geyser, method Subscribe). The fields the bot does
+// not use are left out, prost skips them when decoding, so only the tags below must match
+// upstream. Check them against geyser.proto before moving to another major version.
+pub mod proto {
+    use std::collections::HashMap;
+
+    #[derive(Clone, PartialEq, ::prost::Message)]
+    pub struct SubscribeRequest {
+        #[prost(map = "string, message", tag = "1")]
+        pub accounts: HashMap<String, SubscribeRequestFilterAccounts>,
+        #[prost(map = "string, message", tag = "2")]
+        pub slots: HashMap<String, SubscribeRequestFilterSlots>,
+        #[prost(enumeration = "CommitmentLevel", optional, tag = "6")]
+        pub commitment: Option<i32>,
+    }
+
+    #[derive(Clone, PartialEq, ::prost::Message)]
+    pub struct SubscribeRequestFilterAccounts {
+        #[prost(string, repeated, tag = "2")]
+        pub account: Vec<String>,
+        #[prost(string, repeated, tag = "3")]
+        pub owner: Vec<String>,
+    }
+
+    #[derive(Clone, PartialEq, ::prost::Message)]
+    pub struct SubscribeRequestFilterSlots {}
+
+    #[derive(Clone, PartialEq, ::prost::Message)]
+    pub struct SubscribeUpdate {
+        #[prost(string, repeated, tag = "1")]
+        pub filters: Vec<String>,
+        #[prost(oneof = "subscribe_update::UpdateOneof", tags = "2, 3, 6")]
+        pub update_oneof: Option<subscribe_update::UpdateOneof>,
+    }
+
+    pub mod subscribe_update {
+        #[derive(Clone, PartialEq, ::prost::Oneof)]
+        pub enum UpdateOneof {
+            #[prost(message, tag = "2")]
+            Account(super::SubscribeUpdateAccount),
+            #[prost(message, tag = "3")]
+            Slot(super::SubscribeUpdateSlot),
+            #[prost(message, tag = "6")]
+            Ping(super::SubscribeUpdatePing),
+        }
+    }
+
+    #[derive(Clone, PartialEq, ::prost::Message)]
+    pub struct SubscribeUpdateAccount {
+        #[prost(message, optional, tag = "1")]
+        pub account: Option<SubscribeUpdateAccountInfo>,
+        #[prost(uint64, tag = "2")]
+        pub slot: u64,
+        #[prost(bool, tag = "3")]
+        pub is_startup: bool,
+    }
+
+    #[derive(Clone, PartialEq, ::prost::Message)]
+    pub struct SubscribeUpdateAccountInfo {
+        #[prost(bytes = "vec", tag = "1")]
+        pub pubkey: Vec<u8>,
+        #[prost(uint64, tag = "2")]
+        pub lamports: u64,
+        #[prost(bytes = "vec", tag = "3")]
+        pub owner: Vec<u8>,
+        #[prost(bool, tag = "4")]
+        pub executable: bool,
+        #[prost(uint64, tag = "5")]
+        pub rent_epoch: u64,
+        #[prost(bytes = "vec", tag = "6")]
+        pub data: Vec<u8>,
+        #[prost(uint64, tag = "7")]
+        pub write_version: u64,
+    }
+
+    #[derive(Clone, PartialEq, ::prost::Message)]
+    pub struct SubscribeUpdateSlot {
+        #[prost(uint64, tag = "1")]
+        pub slot: u64,
+    }
+
+    #[derive(Clone, PartialEq, ::prost::Message)]
+    pub struct SubscribeUpdatePing {}
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+    #[repr(i32)]
+    pub enum CommitmentLevel {
+        Processed = 0,
+        Confirmed = 1,
+        Finalized = 2,
+    }
+}
+
+// The geyser grpc stream, one connection for the program and the price accounts.
+pub struct GeyserSource {
+    config: config::Config,
+    // the rpc endpoints the accounts are resynced from
+    endpoints: endpoint::SharedEndpointPool,
+}
+
+impl GeyserSource {
+    pub fn new(config: config::Config, endpoints: endpoint::SharedEndpointPool) -> Self {
+        Self { config, endpoints }
+    }
+}
+
+impl AccountSource for GeyserSource {
+    fn name(&self) -> &'static str {
+        "geyser"
+    }
+
+    fn spawn(
+        &self,
+        channels: SourceChannels,
+        shutdown_rx: watch::Receiver<bool>,
+    ) -> Vec<JoinHandle<anyhow::Result<()>>> {
+        vec![tokio::spawn(subscribe_accounts(
+            self.config.clone(),
+            self.endpoints.clone(),
+            channels,
+            shutdown_rx,
+        ))]
+    }
+}
+
+// Keep the stream alive, reconnect with backoff and fetch all the program accounts
+// again after each reconnect.
+async fn subscribe_accounts(
+    config: config::Config,
+    endpoints: endpoint::SharedEndpointPool,
+    mut channels: SourceChannels,
+    mut shutdown_rx: watch::Receiver<bool>,
+) -> anyhow::Result<()> {
+    let program_pubkey = com::id();
+    let mut price_account: HashSet<Pubkey> = HashSet::new();
+    let mut reconnect = Reconnect::new(Duration::from_secs(
+        config.account_source.max_silence_seconds,
+    ));
+    loop {
+        let exit = run_subscription(
+            &config,
+            &endpoints,
+            &program_pubkey,
+            &mut price_account,
+            &mut channels,
+            &mut shutdown_rx,
+            &mut reconnect,
+        )
+        .await;
+        let (e, failover) = match exit {
+            SubExit::Shutdown => {
+                info!("got shutdown signal, geyser sub exit.");
+                break;
+            }
+            SubExit::ChannelClosed => break,
+            SubExit::Disconnected(e) => (e, false),
+            SubExit::Failover(e) => (e, true),
+        };
+        let delay = reconnect.next_delay(failover);
+        sub::set_connected(&channels.health, &program_pubkey, false);
+        for pubkey in &price_account {
+            sub::set_connected(&channels.health, pubkey, false);
+        }
+        warn!("geyser stream lost: {}, reconnect in {:?}", e, delay);
+        tokio::select! {
+            _ = time::sleep(delay) => {}
+            _ = shutdown_rx.changed() => {
+                info!("got shutdown signal, geyser sub exit.");
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn new_request(
+    program_pubkey: &Pubkey,
+    price_account: &HashSet<Pubkey>,
+) -> proto::SubscribeRequest {
+    let mut accounts = HashMap::new();
+    accounts.insert(
+        PROGRAM_FILTER.to_string(),
+        proto::SubscribeRequestFilterAccounts {
+            account: Vec::new(),
+            owner: vec![program_pubkey.to_string()],
+        },
+    );
+    if !price_account.is_empty() {
+        accounts.insert(
+            PRICE_FILTER.to_string(),
+            proto::SubscribeRequestFilterAccounts {
+                account: price_account.iter().map(|k| k.to_string()).collect(),
+                owner: Vec::new(),
+            },
+        );
+    }
+    // the slots are the heartbeat of the stream
+    let mut slots = HashMap::new();
+    slots.insert(
+        SLOT_FILTER.to_string(),
+        proto::SubscribeRequestFilterSlots {},
+    );
+    proto::SubscribeRequest {
+        accounts,
+        slots,
+        commitment: Some(proto::CommitmentLevel::Processed as i32),
+    }
+}
+
+// The pubkeys are 32 raw bytes.
+fn to_pubkey(bytes: &[u8]) -> Option<Pubkey> {
+    <[u8; 32]>::try_from(bytes).ok().map(Pubkey::new_from_array)
+}
+
+fn to_account(info: proto::SubscribeUpdateAccountInfo) -> Option<(Pubkey, Account)> {
+    let pubkey = to_pubkey(&info.pubkey)?;
+    let owner = to_pubkey(&info.owner)?;
+    Some((
+        pubkey,
+        Account {
+            lamports: info.lamports,
+            data: info.data,
+            owner,
+            executable: info.executable,
+            rent_epoch: info.rent_epoch,
+        },
+    ))
+}
+
+async fn run_subscription(
+    config: &config::Config,
+    endpoints: &endpoint::EndpointPool,
+    program_pubkey: &Pubkey,
+    price_account: &mut HashSet<Pubkey>,
+    channels: &mut SourceChannels,
+    shutdown_rx: &mut watch::Receiver<bool>,
+    reconnect: &mut Reconnect,
+) -> SubExit {
+    let url = config.account_source.geyser_url.clone();
+    let channel = match tonic::transport::Endpoint::from_shared(url) {
+        Ok(e) => match e.connect().await {
+            Ok(c) => c,
+            Err(e) => return SubExit::Disconnected(e.to_string()),
+        },
+        Err(e) => return SubExit::Disconnected(e.to_string()),
+    };
+    let mut grpc = tonic::client::Grpc::new(channel);
+    if let Err(e) = grpc.ready().await {
+        return SubExit::Disconnected(e.to_string());
+    }
+    // The server replaces the filters with every request sent on the stream.
+    let (request_tx, request_rx) = mpsc::unbounded_channel::<proto::SubscribeRequest>();
+    if request_tx
+        .send(new_request(program_pubkey, price_account))
+        .is_err()
+    {
+        return SubExit::ChannelClosed;
+    }
+    let mut request = tonic::Request::new(UnboundedReceiverStream::new(request_rx));
+    if let Some(token) = &config.account_source.geyser_token {
+        match AsciiMetadataValue::try_from(token.as_str()) {
+            Ok(v) => {
+                request.metadata_mut().insert("x-token", v);
+            }
+            Err(e) => error!("invalid geyser token: {}", e),
+        }
+    }
+    let codec =
+        tonic::codec::ProstCodec::<proto::SubscribeRequest, proto::SubscribeUpdate>::default();
+    let mut stream = match grpc
+        .streaming(request, PathAndQuery::from_static(SUBSCRIBE_PATH), codec)
+        .await
+    {
+        Ok(r) => r.into_inner(),
+        Err(e) => return SubExit::Disconnected(e.to_string()),
+    };
+    // Subscribe first, so no update is lost between the fetch and the subscription.
+    if reconnect.resync {
+        info!("resync all program accounts after reconnect ...");
+        if let Err(e) =
+            sub::get_all_program_accounts(endpoints, program_pubkey, &channels.account_watch_tx)
+                .await
+        {
+            return SubExit::Disconnected(format!("resync program accounts error: {}", e));
+        }
+    }
+    sub::set_connected(&channels.health, program_pubkey, true);
+    for pubkey in price_account.iter() {
+        sub::set_connected(&channels.health, pubkey, true);
+    }
+    reconnect.established();
+    info!("start geyser subscription ...");
+    let mut heartbeat_check = time::interval(HEARTBEAT_CHECK_INTERVAL);
+    let mut heartbeat = Heartbeat::new();
+
+    loop {
+        tokio::select! {
+            r = stream.message() => {
+                let update = match r {
+                    Ok(Some(u)) => u,
+                    Ok(None) => return SubExit::Disconnected("geyser stream closed".to_string()),
+                    Err(e) => return SubExit::Disconnected(e.to_string()),
+                };
+                let (info, slot) = match update.update_oneof {
+                    Some(proto::subscribe_update::UpdateOneof::Account(a)) => (a.account, a.slot),
+                    // the stream is alive while the accounts are quiet
+                    Some(proto::subscribe_update::UpdateOneof::Slot(_))
+                    | Some(proto::subscribe_update::UpdateOneof::Ping(_)) => {
+                        heartbeat.beat();
+                        continue;
+                    }
+                    None => continue,
+                };
+                let (pubkey, account) = match info.and_then(to_account) {
+                    Some(v) => v,
+                    None => {
+                        error!("Can not decode geyser account update");
+                        continue;
+                    }
+                };
+                debug!("got geyser account: {},len:{}", pubkey, account.data.len());
+                if update.filters.iter().any(|f| f == PRICE_FILTER) {
+                    sub::touch(&channels.health, &pubkey);
+                    channels.price_watch_tx.send((pubkey, account, slot));
+                } else {
+                    sub::touch(&channels.health, program_pubkey);
+                    if let Err(e) = channels.account_watch_tx.send((pubkey, account, slot)).await {
+                        error!("message channel error:{},geyser sub exit.", e);
+                        return SubExit::ChannelClosed;
+                    }
+                }
+            }
+            r = channels.subscribe_rx.recv() => {
+                let changed = match r {
+                    Some(PriceSub::Subscribe(pubkey)) => {
+                        let changed = price_account.insert(pubkey);
+                        if changed {
+                            sub::set_connected(&channels.health, &pubkey, true);
+                        }
+                        changed
+                    }
+                    Some(PriceSub::Unsubscribe(pubkey)) => {
+                        let changed = price_account.remove(&pubkey);
+                        if changed {
+                            channels.health.remove(&pubkey);
+                        }
+                        changed
+                    }
+                    None => {
+                        debug!("price account sub channel closed");
+                        return SubExit::ChannelClosed;
+                    }
+                };
+                if changed && request_tx.send(new_request(program_pubkey, price_account)).is_err() {
+                    return SubExit::Disconnected("geyser request stream closed".to_string());
+                }
+            }
+            _ = heartbeat_check.tick() => {
+                if let Some(e) = heartbeat.check(reconnect.max_silence) {
+                    return SubExit::Disconnected(e);
+                }
+            }
+            _ = shutdown_rx.changed() => {
+                return SubExit::Shutdown;
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bot::channel;
+    use axum::{extract::Extension, routing::post, Json, Router};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use tokio::net::TcpListener;
+    use tonic::codegen::{http, Body, BoxFuture, Context, Poll, Service, StdError};
+    use tonic::{Request, Response, Status, Streaming};
+
+    type UpdateSender = mpsc::UnboundedSender<Result<proto::SubscribeUpdate, Status>>;
+
+    // A Subscribe stream accepted by the mock server.
+    struct Session {
+        requests: Streaming<proto::SubscribeRequest>,
+        updates_tx: UpdateSender,
+    }
+
+    // A Geyser server that hands every Subscribe stream to the test.
+    #[derive(Clone)]
+    struct MockGeyser {
+        sessions_tx: mpsc::UnboundedSender<Session>,
+    }
+
+    struct SubscribeSvc(mpsc::UnboundedSender<Session>);
+
+    impl tonic::server::StreamingService<proto::SubscribeRequest> for SubscribeSvc {
+        type Response = proto::SubscribeUpdate;
+        type ResponseStream = UnboundedReceiverStream<Result<proto::SubscribeUpdate, Status>>;
+        type Future = BoxFuture<Response<Self::ResponseStream>, Status>;
+
+        fn call(&mut self, request: Request<Streaming<proto::SubscribeRequest>>) -> Self::Future {
+            let sessions_tx = self.0.clone();
+            Box::pin(async move {
+                let (updates_tx, updates_rx) = mpsc::unbounded_channel();
+                let _ = sessions_tx.send(Session {
+                    requests: request.into_inner(),
+                    updates_tx,
+                });
+                Ok(Response::new(UnboundedReceiverStream::new(updates_rx)))
+            })
+        }
+    }
+
+    impl<B> Service<http::Request<B>> for MockGeyser
+    where
+        B: Body + Send + 'static,
+        B::Error: Into<StdError> + Send + 'static,
+    {
+        type Response = http::Response<tonic::body::BoxBody>;
+        type Error = std::convert::Infallible;
+        type Future = BoxFuture<Self::Response, Self::Error>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, req: http::Request<B>) -> Self::Future {
+            let svc = SubscribeSvc(self.sessions_tx.clone());
+            Box::pin(async move {
+                let codec = tonic::codec::ProstCodec::<
+                    proto::SubscribeUpdate,
+                    proto::SubscribeRequest,
+                >::default();
+                let mut grpc = tonic::server::Grpc::new(codec);
+                Ok(grpc.streaming(svc, req).await)
+            })
+        }
+    }
+
+    impl tonic::server::NamedService for MockGeyser {
+        const NAME: &'static str = "geyser.Geyser";
+    }
+
+    async fn start_geyser() -> (String, mpsc::UnboundedReceiver<Session>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let (incoming_tx, incoming_rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                if incoming_tx.send(Ok::<_, std::io::Error>(stream)).is_err() {
+                    break;
+                }
+            }
+        });
+        let (sessions_tx, sessions_rx) = mpsc::unbounded_channel();
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(MockGeyser { sessions_tx })
+                .serve_with_incoming(UnboundedReceiverStream::new(incoming_rx)),
+        );
+        (url, sessions_rx)
+    }
+
+    // A json rpc server without any program account, counting the getProgramAccounts calls.
+    async fn start_rpc() -> (String, Arc<AtomicUsize>) {
+        async fn handle(
+            Extension(calls): Extension<Arc<AtomicUsize>>,
+            Json(req): Json<serde_json::Value>,
+        ) -> Json<serde_json::Value> {
+            let result = match req["method"].as_str() {
+                Some("getVersion") => {
+                    serde_json::json!({"solana-core": "1.10.0", "feature-set": 0})
+                }
+                Some("getProgramAccounts") => {
+                    calls.fetch_add(1, Ordering::SeqCst);
+                    serde_json::json!([])
+                }
+                _ => serde_json::json!([]),
+            };
+            Json(serde_json::json!({"jsonrpc": "2.0", "id": req["id"], "result": result}))
+        }
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let calls = Arc::new(AtomicUsize::new(0));
+        let app = Router::new()
+            .route("/", post(handle))
+            .layer(Extension(calls.clone()));
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+        (url, calls)
+    }
+
+    fn account_update(filter: &str, pubkey: &Pubkey, slot: u64) -> proto::SubscribeUpdate {
+        proto::SubscribeUpdate {
+            filters: vec![filter.to_string()],
+            update_oneof: Some(proto::subscribe_update::UpdateOneof::Account(
+                proto::SubscribeUpdateAccount {
+                    account: Some(proto::SubscribeUpdateAccountInfo {
+                        pubkey: pubkey.to_bytes().to_vec(),
+                        lamports: 1,
+                        owner: com::id().to_bytes().to_vec(),
+                        executable: false,
+                        rent_epoch: 0,
+                        data: vec![1, 2, 3],
+                        write_version: slot,
+                    }),
+                    slot,
+                    is_startup: false,
+                },
+            )),
+        }
+    }
+
+    async fn next_request(session: &mut Session) -> proto::SubscribeRequest {
+        time::timeout(Duration::from_secs(10), session.requests.message())
+            .await
+            .expect("no subscribe request")
+            .unwrap()
+            .unwrap()
+    }
+
+    async fn next_session(sessions_rx: &mut mpsc::UnboundedReceiver<Session>) -> Session {
+        time::timeout(Duration::from_secs(10), sessions_rx.recv())
+            .await
+            .expect("no subscribe stream")
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn geyser_stream_subscribes_and_resyncs_after_reconnect() {
+        let (geyser_url, mut sessions_rx) = start_geyser().await;
+        let (rpc_url, rpc_calls) = start_rpc().await;
+        let mut config = config::Config {
+            cluster: anchor_client::Cluster::Custom(rpc_url.clone(), rpc_url.replace("http", "ws")),
+            ..Default::default()
+        };
+        config.endpoints.extra.clear();
+        config.account_source.geyser_url = geyser_url;
+        config.account_source.max_silence_seconds = 0;
+        let endpoints = Arc::new(endpoint::EndpointPool::new(
+            &config,
+            anchor_client::solana_sdk::commitment_config::CommitmentConfig::confirmed(),
+        ));
+        let metrics: channel::ChannelMetrics = Arc::new(dashmap::DashMap::new());
+        let (account_watch_tx, mut account_rx) = channel::bounded(&metrics, "account", 16);
+        let (price_watch_tx, mut price_rx) = channel::latest(&metrics, "price");
+        let (subscribe_tx, subscribe_rx) = channel::unbounded(&metrics, "price_sub");
+        let health: sub::SharedSubHealth = Arc::new(dashmap::DashMap::new());
+        let channels = SourceChannels {
+            account_watch_tx,
+            price_watch_tx,
+            subscribe_rx,
+            health: health.clone(),
+        };
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let task = tokio::spawn(subscribe_accounts(config, endpoints, channels, shutdown_rx));
+        let program_pubkey = com::id();
+
+        // subscribe: the program filter and the slots, at processed
+        let mut session = next_session(&mut sessions_rx).await;
+        let request = next_request(&mut session).await;
+        assert_eq!(
+            request.accounts[PROGRAM_FILTER].owner,
+            vec![program_pubkey.to_string()]
+        );
+        assert!(!request.accounts.contains_key(PRICE_FILTER));
+        assert!(request.slots.contains_key(SLOT_FILTER));
+        assert_eq!(
+            request.commitment,
+            Some(proto::CommitmentLevel::Processed as i32)
+        );
+
+        // account update of the program
+        let user = Pubkey::new_unique();
+        session
+            .updates_tx
+            .send(Ok(account_update(PROGRAM_FILTER, &user, 7)))
+            .unwrap();
+        let (pubkey, account, slot) = time::timeout(Duration::from_secs(10), account_rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!((pubkey, slot), (user, 7));
+        assert_eq!(account.data, vec![1, 2, 3]);
+        assert!(health.get(&program_pubkey).unwrap().connected);
+
+        // a subscribed price account is added to the filters and routed to the price channel
+        let price = Pubkey::new_unique();
+        subscribe_tx.send(PriceSub::Subscribe(price)).unwrap();
+        let request = next_request(&mut session).await;
+        assert_eq!(
+            request.accounts[PRICE_FILTER].account,
+            vec![price.to_string()]
+        );
+        session
+            .updates_tx
+            .send(Ok(account_update(PRICE_FILTER, &price, 8)))
+            .unwrap();
+        let (pubkey, _, slot) = time::timeout(Duration::from_secs(10), price_rx.recv())
+            .await
+            .unwrap();
+        assert_eq!((pubkey, slot), (price, 8));
+        assert_eq!(rpc_calls.load(Ordering::SeqCst), 0);
+
+        // the server ends the stream, the client reconnects with the price filter and resyncs
+        drop(session);
+        let mut session = next_session(&mut sessions_rx).await;
+        let request = next_request(&mut session).await;
+        assert_eq!(
+            request.accounts[PRICE_FILTER].account,
+            vec![price.to_string()]
+        );
+        session
+            .updates_tx
+            .send(Ok(account_update(PROGRAM_FILTER, &user, 9)))
+            .unwrap();
+        let (_, _, slot) = time::timeout(Duration::from_secs(10), account_rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(slot, 9);
+        // one getProgramAccounts per account kind of the snapshot
+        assert_eq!(rpc_calls.load(Ordering::SeqCst), 3);
+        assert_eq!(health.get(&program_pubkey).unwrap().reconnects, 1);
+
+        let _ = shutdown_tx.send(true);
+        time::timeout(Duration::from_secs(10), task)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+    }
+}
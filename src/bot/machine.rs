@@ -1,19 +1,20 @@
-use super::{price, storage};
+use super::{channel, price, queue, risk, storage, sub, submitter};
 use crate::{client, com, config};
 use anchor_client::anchor_lang::AccountDeserialize;
 use anchor_client::solana_sdk::{account::Account, pubkey::Pubkey};
 use bond::com as bcom;
 use bond::state::{market, position, user};
 use chrono::{Datelike, NaiveDate, Utc};
-use dashmap::DashMap;
+use dashmap::{mapref::entry::Entry, DashMap, DashSet};
 use log::{debug, error, info, warn};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fmt;
 use std::str::FromStr;
-use std::sync::Arc;
-use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
 use tokio::{
-    sync::{mpsc, oneshot},
+    sync::{broadcast, oneshot},
     task::JoinHandle,
     time,
 };
@@ -90,9 +91,48 @@ type DmPrice = DashMap<Pubkey, market::Price>;
 type DmUserPosition = DashMap<Pubkey, DmPosition>;
 // key is price account,value is market account
 type DmIdxPriceMarket = DashMap<Pubkey, Pubkey>;
+// key is chainlink price account, value is the latest chainlink price
+type DmChainlinkPrice = DashMap<Pubkey, price::PriceData>;
+// key is pyth price account, value is the quote the market price was taken from
+type DmPriceData = DashMap<Pubkey, price::PriceData>;
+// key is market account, value is the users having open positions in it
+type DmIdxMarketUser = DashMap<Pubkey, DashSet<Pubkey>>;
+// key is market account, value is the reason its price is not trusted
+type DmHaltedMarket = DashMap<Pubkey, String>;
 // key is user account pubkey
-type DmUserDynamicData = DashMap<Pubkey, UserDynamicData>;
-type DmPositionDynamicData = DashMap<Pubkey, PositionDynamicData>;
+type DmUserDynamicData = DashMap<Pubkey, risk::UserDynamicData>;
+type DmPositionDynamicData = DashMap<Pubkey, risk::PositionDynamicData>;
+// key is user account, value is the lowest margin rate of its full and independent positions
+type DmMarginRate = DashMap<Pubkey, f64>;
+// key is user account or position account, value is the number of margin call levels it is under
+type DmMarginCallLevel = DashMap<Pubkey, usize>;
+// key is program or price account, value is the context slot of its last applied update
+type DmSlot = DashMap<Pubkey, u64>;
+
+// The unix time of the slots, estimated from the latest on-chain time seen at a slot.
+#[derive(Default)]
+pub struct SlotClock {
+    latest: RwLock<Option<(u64, i64)>>,
+}
+
+impl SlotClock {
+    // Record the on-chain unix time of a slot, e.g. the publish time of a pyth price.
+    pub fn observe(&self, slot: u64, timestamp: i64) {
+        let mut latest = self.latest.write().unwrap();
+        match *latest {
+            Some((s, _)) if s > slot => {}
+            _ => *latest = Some((slot, timestamp)),
+        }
+    }
+
+    // The unix time of the slot, the time of the bot until an on-chain time is seen.
+    pub fn time(&self, slot: u64) -> i64 {
+        match *self.latest.read().unwrap() {
+            Some((s, t)) => t + (slot as i64 - s as i64) * com::SLOT_MS as i64 / 1000,
+            None => Utc::now().timestamp(),
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct StateMap {
@@ -101,38 +141,60 @@ pub struct StateMap {
     pub position: DmUserPosition,
     pub price_account: DmPrice,
     pub price_idx_price_account: DmIdxPriceMarket,
+    pub chainlink_price: DmChainlinkPrice,
+    pub price_data_idx: DmPriceData,
+    pub halted_market: DmHaltedMarket,
+    pub market_idx_user: DmIdxMarketUser,
+    // market accounts whose price moved
+    pub price_event_tx: flume::Sender<Pubkey>,
+    pub price_event_rx: flume::Receiver<Pubkey>,
     pub user_dynamic_idx: DmUserDynamicData,
     pub position_dynamic_idx: DmPositionDynamicData,
+    pub margin_rate_idx: DmMarginRate,
+    pub margin_call_level: DmMarginCallLevel,
+    // subscribe to receive the margin calls
+    pub margin_call_tx: broadcast::Sender<risk::MarginCall>,
+    pub sub_health: sub::SharedSubHealth,
+    pub slot_idx: DmSlot,
+    pub slot_clock: Arc<SlotClock>,
+    pub channels: channel::ChannelMetrics,
+    // set once the startup snapshot of the program accounts is loaded
+    pub snapshot_ready: Arc<AtomicBool>,
     pub storage: storage::Storage,
 }
 pub type SharedStateMap = Arc<StateMap>;
+const PRICE_EVENT_CAPACITY: usize = 1024;
+// program account updates waiting for keep_account, the sources wait when it is full
+const ACCOUNT_CHANNEL_CAPACITY: usize = 4096;
+// account updates written to the local storage in one batch at most
+const MAX_WRITE_BATCH: usize = 256;
+// a failed write batch is tried again this many times
+const MAX_APPLY_ATTEMPTS: u32 = 3;
+const APPLY_RETRY_DELAY: time::Duration = time::Duration::from_millis(100);
+const MARGIN_CALL_CAPACITY: usize = 1024;
+const IN_FLIGHT_POLL_INTERVAL: time::Duration = time::Duration::from_millis(500);
+// getMultipleAccounts takes at most this many accounts
+const MAX_MULTIPLE_ACCOUNTS: usize = 100;
+
+// The funding of a position, positive values are credited to the user.
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct UserDynamicData {
-    pub profit: f64,
-    pub margin_percentage: f64,
-    pub equity: f64,
-    pub profit_rate: f64,
+pub struct FundingPosition {
+    pub pubkey: Pubkey,
+    pub market_account: Pubkey,
+    pub full: bool,
+    pub fund: com::Money,
 }
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct PositionDynamicData {
-    pub profit_rate: f64,
-}
-
-impl Default for UserDynamicData {
-    fn default() -> Self {
-        UserDynamicData {
-            profit: 0.0,
-            margin_percentage: 0.0,
-            equity: 0.0,
-            profit_rate: 0.0,
-        }
-    }
-}
-impl Default for PositionDynamicData {
-    fn default() -> Self {
-        PositionDynamicData { profit_rate: 0.0 }
-    }
+pub struct FundingRecord {
+    pub user: Pubkey,
+    // start timestamp of the eight hour funding period
+    pub period: i64,
+    pub full_fund: com::Money,
+    pub independent_fund: com::Money,
+    pub positions: Vec<FundingPosition>,
 }
+const FUNDING_PERIOD: i64 = 8 * 3600;
+const PRICE_GUARD_INTERVAL: time::Duration = time::Duration::from_secs(5);
 
 impl StateMap {
     pub fn new(config: config::Config) -> anyhow::Result<Self> {
@@ -142,8 +204,16 @@ impl StateMap {
         let position: DmUserPosition = DashMap::new();
         let price_account: DmPrice = DashMap::new();
         let price_idx_price_account: DmIdxPriceMarket = DashMap::new();
+        let chainlink_price: DmChainlinkPrice = DashMap::new();
+        let price_data_idx: DmPriceData = DashMap::new();
+        let halted_market: DmHaltedMarket = DashMap::new();
+        let market_idx_user: DmIdxMarketUser = DashMap::new();
+        let (price_event_tx, price_event_rx) = flume::bounded::<Pubkey>(PRICE_EVENT_CAPACITY);
         let user_dynamic_idx: DmUserDynamicData = DashMap::new();
         let position_dynamic_idx: DmPositionDynamicData = DashMap::new();
+        let margin_rate_idx: DmMarginRate = DashMap::new();
+        let margin_call_level: DmMarginCallLevel = DashMap::new();
+        let (margin_call_tx, _) = broadcast::channel::<risk::MarginCall>(MARGIN_CALL_CAPACITY);
         Ok(Self {
             market,
             user,
@@ -151,15 +221,26 @@ impl StateMap {
             storage,
             price_account,
             price_idx_price_account,
+            chainlink_price,
+            price_data_idx,
+            halted_market,
+            market_idx_user,
+            price_event_tx,
+            price_event_rx,
             user_dynamic_idx,
             position_dynamic_idx,
+            margin_rate_idx,
+            margin_call_level,
+            margin_call_tx,
+            sub_health: Arc::new(DashMap::new()),
+            slot_idx: DashMap::new(),
+            slot_clock: Arc::new(SlotClock::default()),
+            channels: Arc::new(DashMap::new()),
+            snapshot_ready: Arc::new(AtomicBool::new(false)),
         })
     }
 
-    pub fn load_active_account_from_local(
-        &mut self,
-        pyth_price_account_sub: mpsc::UnboundedSender<Pubkey>,
-    ) -> anyhow::Result<()> {
+    pub fn load_active_account_from_local(&mut self) -> anyhow::Result<()> {
         info!("start load active account from local!");
         let p = storage::Prefix::Active;
         let r = self.storage.scan_prefix(&p);
@@ -174,23 +255,13 @@ impl StateMap {
                     debug!("load pubkey from db : {}", pk);
                     let pbk = Pubkey::try_from(pk.as_str())
                         .map_err(|e| com::CliError::Unknown(e.to_string()))?;
-                    let values: Account = serde_json::from_slice(v.to_vec().as_slice())
-                        .map_err(|e| com::CliError::JsonError(e.to_string()))?;
+                    let values: Account = storage::decode(&v)?;
                     let s: State = (&values).into();
                     match s {
                         State::Market(m) => {
+                            // the price accounts are subscribed by subscribe_price_accounts
                             self.price_idx_price_account
                                 .insert((&m).pyth_price_account, pbk);
-                            // send price sub
-                            match pyth_price_account_sub.send((&m).pyth_price_account) {
-                                Ok(_) => {
-                                    debug!("Send pyth price account to sub success!");
-                                }
-                                Err(e) => {
-                                    info!("Send pyth price to sub error: {}", e);
-                                }
-                            }
-
                             self.price_idx_price_account
                                 .insert((&m).chianlink_price_account, pbk);
                             self.market.insert(pbk, m);
@@ -199,16 +270,23 @@ impl StateMap {
                             self.user.insert(pbk, m);
                         }
                         State::Position(m) => {
-                            match self.position.get(&m.authority) {
+                            // positions are keyed by the user account, as in keep_account
+                            let (user_account, _bump) = Pubkey::find_program_address(
+                                &[bcom::USER_ACCOUNT_SEED, &m.authority.to_bytes()],
+                                &com::id(),
+                            );
+                            let market_account = m.market_account;
+                            match self.position.get(&user_account) {
                                 Some(p) => {
                                     p.insert(pbk, m);
                                 }
                                 None => {
                                     let p: DmPosition = dashmap::DashMap::new();
-                                    p.insert(pbk, m.clone());
-                                    self.position.insert(m.authority, p);
+                                    p.insert(pbk, m);
+                                    self.position.insert(user_account, p);
                                 }
                             };
+                            self.update_market_idx_user(&user_account, &market_account);
                         }
                         State::None => {}
                     }
@@ -218,27 +296,195 @@ impl StateMap {
                 }
             }
         }
+        match self.storage.get_slots() {
+            Ok(slots) => {
+                for (pubkey, slot) in slots {
+                    self.slot_idx.insert(pubkey, slot);
+                }
+            }
+            Err(e) => {
+                error!("load account slots from db error: {}", e);
+            }
+        }
         info!("complete load active account from local!");
         Ok(())
     }
+
+    // Subscribe the price accounts of the known markets, once the subscriber is running.
+    pub fn subscribe_price_accounts(
+        &self,
+        pyth_price_account_sub: &channel::UnboundedSender<sub::PriceSub>,
+    ) {
+        let price_accounts: Vec<Pubkey> = self
+            .market
+            .iter()
+            .flat_map(|m| [m.pyth_price_account, m.chianlink_price_account])
+            .collect();
+        for price_account in price_accounts {
+            send_price_sub(
+                pyth_price_account_sub,
+                sub::PriceSub::Subscribe(price_account),
+            );
+        }
+    }
+
+    // Record the slot of an account update, return false if a newer update was already applied.
+    // The slots of every source are compared, the subscriptions and the price resync read at
+    // processed and the snapshots at confirmed commitment. Both are slots of the same chain, a
+    // confirmed read at slot S is never newer than a processed update at a slot above S, so the
+    // comparison only keeps the newest state. A processed update of a fork that is dropped later
+    // is kept until an update at a higher slot replaces it.
+    // The slot of an account is forgotten when it leaves the active set.
+    pub fn check_slot(&self, pubkey: &Pubkey, slot: u64) -> bool {
+        match self.slot_idx.entry(*pubkey) {
+            Entry::Occupied(mut e) => {
+                if *e.get() > slot {
+                    return false;
+                }
+                e.insert(slot);
+            }
+            Entry::Vacant(e) => {
+                e.insert(slot);
+            }
+        }
+        true
+    }
+
+    // Keep the market to users index in line with the open positions of the user.
+    pub fn update_market_idx_user(&self, user: &Pubkey, market: &Pubkey) {
+        let has_position = match self.position.get(user) {
+            Some(ps) => ps.iter().any(|p| p.market_account == *market),
+            None => false,
+        };
+        if has_position {
+            self.market_idx_user
+                .entry(*market)
+                .or_insert_with(DashSet::new)
+                .insert(*user);
+        } else if let Some(users) = self.market_idx_user.get(market) {
+            users.remove(user);
+        }
+    }
+
+    // Halt the markets whose price is missing or has not been updated in time.
+    // A halted market resumes on the next trusted price update.
+    pub fn check_price_guard(&self, config: &config::Config) {
+        let now = Utc::now().timestamp();
+        for m in &self.market {
+            let guard = config.get_market_config(&m.pair);
+            let reason = match self.price_data_idx.get(&m.pyth_price_account) {
+                Some(p) => p.check(now, &guard).err(),
+                None => Some("price none".to_string()),
+            };
+            if let Some(r) = reason {
+                if !self.halted_market.contains_key(m.key()) {
+                    warn!("halt market {}: {}", m.pair, r);
+                }
+                self.halted_market.insert(*m.key(), r);
+            }
+        }
+    }
+
+    // Return the reason if the program accounts may be stale, the prices are checked by the price guard.
+    pub fn check_sub_health(&self) -> Result<(), String> {
+        if !self.snapshot_ready.load(Ordering::Acquire) {
+            return Err("the snapshot of the program accounts is not loaded yet".to_string());
+        }
+        match self.sub_health.get(&com::id()) {
+            Some(h) if h.connected => Ok(()),
+            Some(h) => Err(format!(
+                "program account subscription is disconnected since {}",
+                h.updated_at
+            )),
+            None => Err("program account subscription is not connected yet".to_string()),
+        }
+    }
+
+    // Copy the accounts and prices the risk of the user depends on.
+    // Return None if the user or its positions are not loaded.
+    pub fn user_snapshot(&self, user_pubkey: &Pubkey) -> Option<risk::UserSnapshot> {
+        let account = self.user.get(user_pubkey)?.value().clone();
+        let positions: Vec<(Pubkey, position::Position)> = self
+            .position
+            .get(user_pubkey)?
+            .iter()
+            .map(|v| (*v.key(), v.value().clone()))
+            .collect();
+        Some(self.snapshot_of(*user_pubkey, account, positions))
+    }
+
+    // Copy the prices the risk of the user accounts depends on.
+    pub fn snapshot_of(
+        &self,
+        user_pubkey: Pubkey,
+        account: user::UserAccount,
+        positions: Vec<(Pubkey, position::Position)>,
+    ) -> risk::UserSnapshot {
+        let mut quotes = HashMap::new();
+        let markets = positions.iter().map(|(_, v)| v.market_account).chain(
+            account
+                .open_full_position_headers
+                .iter()
+                .map(|h| h.market.to_pubkey().0),
+        );
+        for market_pubkey in markets {
+            if quotes.contains_key(&market_pubkey) {
+                continue;
+            }
+            let market = match self.market.get(&market_pubkey) {
+                Some(m) => m.value().clone(),
+                None => continue,
+            };
+            let price = match self.price_account.get(&market.pyth_price_account) {
+                Some(p) => market::Price {
+                    buy_price: p.buy_price,
+                    sell_price: p.sell_price,
+                    real_price: p.real_price,
+                    spread: p.spread,
+                },
+                None => continue,
+            };
+            let halted = self
+                .halted_market
+                .get(&market_pubkey)
+                .map(|r| r.value().clone());
+            quotes.insert(
+                market_pubkey,
+                risk::MarketQuote {
+                    market,
+                    price,
+                    halted,
+                },
+            );
+        }
+        risk::UserSnapshot {
+            user: user_pubkey,
+            account,
+            positions,
+            quotes,
+        }
+    }
 }
 pub struct Watch {
     account_shutdown_tx: oneshot::Sender<()>,
     price_shutdown_tx: oneshot::Sender<()>,
-    pub account_watch_tx: UnboundedSender<(Pubkey, Account)>,
-    pub price_watch_tx: UnboundedSender<(Pubkey, Account)>,
+    pub account_watch_tx: channel::Sender<sub::AccountUpdate>,
+    pub price_watch_tx: channel::LatestSender,
     aw: JoinHandle<anyhow::Result<()>>,
     pw: JoinHandle<anyhow::Result<()>>,
 }
 
 impl Watch {
     pub async fn new<'a>(
+        config: config::Config,
         mp: SharedStateMap,
-        pyth_price_account_sub: mpsc::UnboundedSender<Pubkey>,
+        pyth_price_account_sub: channel::UnboundedSender<sub::PriceSub>,
     ) -> Self {
-        let (account_watch_tx, account_watch_rx) = mpsc::unbounded_channel::<(Pubkey, Account)>();
+        let (account_watch_tx, account_watch_rx) =
+            channel::bounded(&mp.channels, "account_watch", ACCOUNT_CHANNEL_CAPACITY);
         let (account_shutdown_tx, account_shutdown_rx) = oneshot::channel::<()>();
-        let (price_watch_tx, price_watch_rx) = mpsc::unbounded_channel::<(Pubkey, Account)>();
+        // only the latest update of a price account matters
+        let (price_watch_tx, price_watch_rx) = channel::latest(&mp.channels, "price_watch");
         let (price_shutdown_tx, price_shutdown_rx) = oneshot::channel::<()>();
         Self {
             account_shutdown_tx,
@@ -251,7 +497,12 @@ impl Watch {
                 account_shutdown_rx,
                 pyth_price_account_sub,
             )),
-            pw: tokio::spawn(watch_price(mp.clone(), price_watch_rx, price_shutdown_rx)),
+            pw: tokio::spawn(watch_price(
+                config,
+                mp.clone(),
+                price_watch_rx,
+                price_shutdown_rx,
+            )),
         }
     }
 
@@ -265,9 +516,9 @@ impl Watch {
 
 async fn watch_account<'a>(
     mp: SharedStateMap,
-    mut watch_rx: UnboundedReceiver<(Pubkey, Account)>,
+    mut watch_rx: channel::Receiver<sub::AccountUpdate>,
     mut shutdown_rx: oneshot::Receiver<()>,
-    pyth_price_account_sub: mpsc::UnboundedSender<Pubkey>,
+    pyth_price_account_sub: channel::UnboundedSender<sub::PriceSub>,
 ) -> anyhow::Result<()> {
     info!("start scale program account watch ...");
     loop {
@@ -279,9 +530,31 @@ async fn watch_account<'a>(
             r = watch_rx.recv()=>{
                 match r {
                     Some(rs)=>{
-                        let (pubkey,account) = rs;
-                        debug!("account channel got data : {:?},{:?}",pubkey,account);
-                        keep_account(mp.clone(), pubkey, account,pyth_price_account_sub.clone());
+                        // write the updates already in the channel together
+                        let mut batch = storage::WriteBatch::default();
+                        let mut next = Some(rs);
+                        let mut n = 0;
+                        while let Some((pubkey,account,slot)) = next {
+                            debug!("account channel got data : {:?},{:?},slot:{}",pubkey,account,slot);
+                            if mp.check_slot(&pubkey, slot) {
+                                if keep_account(mp.clone(), pubkey, account, slot, &mut batch, &pyth_price_account_sub) {
+                                    batch.save_slot(&pubkey, slot);
+                                } else {
+                                    // not active, the slot goes with the history write
+                                    mp.slot_idx.remove(&pubkey);
+                                    batch.remove_slot(&pubkey);
+                                }
+                            } else {
+                                debug!("drop the stale update of account {} at slot {}",pubkey,slot);
+                            }
+                            n += 1;
+                            next = if n < MAX_WRITE_BATCH {
+                                watch_rx.recv_ready().await
+                            } else {
+                                None
+                            };
+                        }
+                        apply_batch(&mp, batch).await;
                     }
                     None=>{
                         debug!("account channel got none : {:?}",r);
@@ -294,8 +567,9 @@ async fn watch_account<'a>(
 }
 
 async fn watch_price(
+    config: config::Config,
     mp: SharedStateMap,
-    mut watch_rx: UnboundedReceiver<(Pubkey, Account)>,
+    mut watch_rx: channel::LatestReceiver,
     mut shutdown_rx: oneshot::Receiver<()>,
 ) -> anyhow::Result<()> {
     info!("start price account watch...");
@@ -305,37 +579,98 @@ async fn watch_price(
                 info!("got shutdown signal,break watch price!");
                 break;
             },
-            r = watch_rx.recv()=>{
-                match r {
-                    Some(rs)=>{
-                        let (pubkey,account) = rs;
-                        keep_price(mp.clone(), pubkey, account);
-                    }
-                    None=>{}
+            rs = watch_rx.recv()=>{
+                let (pubkey,account,slot) = rs;
+                if !mp.check_slot(&pubkey, slot) {
+                    debug!("drop the stale update of price account {} at slot {}",pubkey,slot);
+                    continue;
                 }
+                keep_price(&config, mp.clone(), pubkey, account, slot);
             }
         }
     }
     Ok(())
 }
 
-fn keep_price(mp: SharedStateMap, pubkey: Pubkey, mut account: Account) {
+fn keep_price(
+    config: &config::Config,
+    mp: SharedStateMap,
+    pubkey: Pubkey,
+    mut account: Account,
+    slot: u64,
+) {
     match mp.price_idx_price_account.get(&pubkey) {
         Some(k) => {
             if let Some(m) = mp.market.get(&k) {
-                match price::get_price_from_pyth(&pubkey, &mut account) {
+                let guard = config.get_market_config(&m.pair);
+                let rs = if pubkey == m.chianlink_price_account {
+                    match price::get_price_from_chainlink(&account) {
+                        Ok(p) => {
+                            mp.chainlink_price.insert(pubkey, p.clone());
+                            // chainlink only takes over when pyth is missing or untrusted
+                            let now = Utc::now().timestamp();
+                            match mp.price_data_idx.get(&m.pyth_price_account) {
+                                Some(d)
+                                    if d.source == price::PriceSource::Pyth
+                                        && d.check(now, &guard).is_ok() =>
+                                {
+                                    return;
+                                }
+                                _ => p
+                                    .check(now, &guard)
+                                    .map(|_| p)
+                                    .map_err(|e| com::CliError::PriceError(e).into()),
+                            }
+                        }
+                        Err(e) => {
+                            error!("{}", e);
+                            return;
+                        }
+                    }
+                } else {
+                    let fallback = mp
+                        .chainlink_price
+                        .get(&m.chianlink_price_account)
+                        .map(|p| p.value().clone());
+                    price::get_price(&pubkey, &mut account, fallback, &guard)
+                };
+                match rs {
                     Ok(p) => {
+                        if p.source == price::PriceSource::Pyth {
+                            mp.slot_clock.observe(slot, p.publish_time);
+                        }
                         let spread = m.spread;
                         let price = market::Price {
-                            buy_price: com::f64_round(p + spread),
-                            sell_price: com::f64_round(p - spread),
-                            real_price: p,
+                            buy_price: com::f64_round(p.price + spread),
+                            sell_price: com::f64_round(p.price - spread),
+                            real_price: p.price,
                             spread,
                         };
-                        mp.price_account.insert(pubkey, price);
+                        debug!(
+                            "update price of market {} from {}: {}",
+                            m.pair, p.source, p.price
+                        );
+                        let moved = match mp.price_account.get(&m.pyth_price_account) {
+                            Some(old) => old.real_price != price.real_price,
+                            None => true,
+                        };
+                        mp.price_account.insert(m.pyth_price_account, price);
+                        mp.price_data_idx.insert(m.pyth_price_account, p);
+                        if moved {
+                            if let Err(e) = mp.price_event_tx.try_send(*m.key()) {
+                                debug!("price event of market {} dropped: {}", m.pair, e);
+                            }
+                        }
+                        if mp.halted_market.remove(m.key()).is_some() {
+                            info!("market {} resumed, price is trusted again", m.pair);
+                        }
                     }
                     Err(e) => {
                         error!("{}", e);
+                        if !mp.halted_market.contains_key(m.key()) {
+                            warn!("halt market {}: {}", m.pair, e);
+                        }
+                        mp.halted_market.insert(*m.key(), e.to_string());
                     }
                 }
             } else {
@@ -350,15 +685,20 @@ fn keep_price(mp: SharedStateMap, pubkey: Pubkey, mut account: Account) {
         }
     }
 }
+// Apply an account update, return true if the account is active after it.
 fn keep_account(
     mp: SharedStateMap,
     pubkey: Pubkey,
     account: Account,
-    pyth_price_account_sub: mpsc::UnboundedSender<Pubkey>,
-) {
+    slot: u64,
+    batch: &mut storage::WriteBatch,
+    pyth_price_account_sub: &channel::UnboundedSender<sub::PriceSub>,
+) -> bool {
     let s: State = (&account).into();
     let tag = s.to_string();
     let keys = storage::Keys::new(storage::Prefix::Active);
+    // a position is indexed as closed at the time of the update that closed it
+    let close_time = mp.slot_clock.time(slot);
     match s {
         State::Market(m) => {
             let pyth_account = m.pyth_price_account;
@@ -368,31 +708,58 @@ fn keep_account(
                 mp.market.remove(&pubkey);
                 mp.price_idx_price_account.remove(&pyth_account);
                 mp.price_idx_price_account.remove(&chainlink_account);
-                save_as_history(mp, &mut keys, &account);
+                mp.chainlink_price.remove(&chainlink_account);
+                release_price_accounts(
+                    &mp,
+                    [pyth_account, chainlink_account],
+                    pyth_price_account_sub,
+                );
+                save_as_history(batch, &mut keys, &account, close_time);
+                false
             } else {
+                let old = mp
+                    .market
+                    .get(&pubkey)
+                    .map(|v| [v.pyth_price_account, v.chianlink_price_account]);
                 mp.market.insert(pubkey, m);
                 mp.price_idx_price_account.insert(pyth_account, pubkey);
                 mp.price_idx_price_account.insert(chainlink_account, pubkey);
-                save_to_active(mp, &mut keys, &account);
-                // send price sub
-                match pyth_price_account_sub.send(pyth_account) {
-                    Ok(_) => {
-                        debug!("Send pyth price account to sub success!");
+                save_to_active(batch, &mut keys, &account);
+                // the price accounts of a known market are subscribed already, most market
+                // updates do not change them
+                let new = [pyth_account, chainlink_account];
+                if old != Some(new) {
+                    if let Some(old) = old {
+                        let replaced: Vec<Pubkey> =
+                            old.into_iter().filter(|k| !new.contains(k)).collect();
+                        for price_account in &replaced {
+                            mp.price_idx_price_account
+                                .remove_if(price_account, |_, v| *v == pubkey);
+                            mp.chainlink_price.remove(price_account);
+                        }
+                        release_price_accounts(&mp, replaced, pyth_price_account_sub);
                     }
-                    Err(e) => {
-                        info!("Send pyth price to sub error: {}", e);
+                    for price_account in new {
+                        send_price_sub(
+                            pyth_price_account_sub,
+                            sub::PriceSub::Subscribe(price_account),
+                        );
                     }
                 }
+                true
             }
         }
         State::User(m) => {
             let mut keys = keys.add(tag).add(pubkey.to_string());
             if account.lamports <= 0 {
                 mp.user.remove(&pubkey);
-                save_as_history(mp, &mut keys, &account);
+                mp.margin_rate_idx.remove(&pubkey);
+                save_as_history(batch, &mut keys, &account, close_time);
+                false
             } else {
                 mp.user.insert(pubkey, m);
-                save_to_active(mp, &mut keys, &account);
+                save_to_active(batch, &mut keys, &account);
+                true
             }
         }
         State::Position(m) => {
@@ -410,6 +777,7 @@ fn keep_account(
                 || m.position_status == position::PositionStatus::ForceClosing
             {
                 mp.position.remove(&pubkey);
+                mp.margin_call_level.remove(&pubkey);
                 match mp.position.get(&user_account) {
                     Some(p) => {
                         p.remove(&pubkey);
@@ -418,8 +786,20 @@ fn keep_account(
                         // nothing to do
                     }
                 };
-                save_as_history(mp, &mut keys, &account);
+                mp.update_market_idx_user(&user_account, &m.market_account);
+                save_as_history(batch, &mut keys, &account, close_time);
+                false
             } else {
+                // a closed position never opens again, without its slot an older update
+                // e.g. from a resync snapshot would bring it back
+                let known = match mp.position.get(&user_account) {
+                    Some(p) => p.contains_key(&pubkey),
+                    None => false,
+                };
+                if !known && is_history(&mp, batch, &keys) {
+                    debug!("drop the update of closed position {}", pubkey);
+                    return false;
+                }
                 match mp.position.get(&user_account) {
                     Some(p) => {
                         p.insert(pubkey, m.clone());
@@ -430,7 +810,9 @@ fn keep_account(
                         mp.position.insert(user_account, p);
                     }
                 };
-                save_to_active(mp, &mut keys, &account);
+                mp.update_market_idx_user(&user_account, &m.market_account);
+                save_to_active(batch, &mut keys, &account);
+                true
             }
         }
         State::None => {
@@ -438,12 +820,111 @@ fn keep_account(
                 "Unrecognized structure of account: {:?},{:?}",
                 pubkey, account,
             );
+            false
         }
     }
 }
 
-fn save_as_history(mp: SharedStateMap, ks: &mut storage::Keys, account: &Account) {
-    match mp.storage.save_as_history(ks, account) {
+fn is_history(mp: &StateMap, batch: &storage::WriteBatch, ks: &storage::Keys) -> bool {
+    if batch.is_history(ks) {
+        return true;
+    }
+    match mp.storage.is_history(ks) {
+        Ok(v) => v,
+        Err(e) => {
+            error!(
+                "check history of account error:{},account:{}",
+                e,
+                ks.get_storage_key()
+            );
+            false
+        }
+    }
+}
+
+// Stop the price subscriptions no other market uses.
+fn release_price_accounts(
+    mp: &SharedStateMap,
+    price_accounts: impl IntoIterator<Item = Pubkey>,
+    price_account_sub: &channel::UnboundedSender<sub::PriceSub>,
+) {
+    for price_account in price_accounts {
+        let used = mp.market.iter().any(|v| {
+            v.pyth_price_account == price_account || v.chianlink_price_account == price_account
+        });
+        if !used {
+            mp.slot_idx.remove(&price_account);
+            send_price_sub(price_account_sub, sub::PriceSub::Unsubscribe(price_account));
+        }
+    }
+}
+
+// Subscribe and unsubscribe are never dropped, the channel is unbounded so the account watch
+// never waits on the subscriber, which itself may wait on the account watch.
+fn send_price_sub(price_account_sub: &channel::UnboundedSender<sub::PriceSub>, msg: sub::PriceSub) {
+    let pubkey = match msg {
+        sub::PriceSub::Subscribe(k) | sub::PriceSub::Unsubscribe(k) => k,
+    };
+    if pubkey == Pubkey::default() {
+        return;
+    }
+    match price_account_sub.send(msg) {
+        Ok(_) => {
+            debug!("Send price account {:?} to sub success!", msg);
+        }
+        Err(e) => {
+            info!("Send price account to sub error: {}", e);
+        }
+    }
+}
+
+// Write the batch, retry it if it fails. A batch that can not be written rolls the slots of
+// its accounts back to the saved ones, so their next update is written again.
+async fn apply_batch(mp: &SharedStateMap, batch: storage::WriteBatch) {
+    let n = batch.len();
+    if n == 0 {
+        return;
+    }
+    for attempt in 1..=MAX_APPLY_ATTEMPTS {
+        match mp.storage.apply(&batch) {
+            Ok(()) => {
+                debug!("apply a batch of {} writes success!", n);
+                return;
+            }
+            Err(e) => {
+                error!(
+                    "apply a batch of {} writes error:{}, attempt: {}",
+                    n, e, attempt
+                );
+            }
+        }
+        if attempt < MAX_APPLY_ATTEMPTS {
+            time::sleep(APPLY_RETRY_DELAY).await;
+        }
+    }
+    for pubkey in batch.slot_accounts() {
+        match mp.storage.get_slot(pubkey) {
+            Ok(Some(slot)) => {
+                mp.slot_idx.insert(*pubkey, slot);
+            }
+            Ok(None) => {
+                mp.slot_idx.remove(pubkey);
+            }
+            Err(e) => {
+                error!("get slot of account {} error:{}", pubkey, e);
+                mp.slot_idx.remove(pubkey);
+            }
+        }
+    }
+}
+
+fn save_as_history(
+    batch: &mut storage::WriteBatch,
+    ks: &mut storage::Keys,
+    account: &Account,
+    close_time: i64,
+) {
+    match batch.save_as_history(ks, account, close_time) {
         Ok(()) => {
             debug!(
                 "save a account as history success!account:{}",
@@ -460,8 +941,8 @@ fn save_as_history(mp: SharedStateMap, ks: &mut storage::Keys, account: &Account
     }
 }
 
-fn save_to_active(mp: SharedStateMap, ks: &mut storage::Keys, account: &Account) {
-    match mp.storage.save_to_active(ks, account) {
+fn save_to_active(batch: &mut storage::WriteBatch, ks: &mut storage::Keys, account: &Account) {
+    match batch.save_to_active(ks, account) {
         Ok(()) => {
             debug!(
                 "save a account as active success!account:{}",
@@ -480,27 +961,44 @@ fn save_to_active(mp: SharedStateMap, ks: &mut storage::Keys, account: &Account)
 
 pub struct Liquidation {
     shutdown_tx: oneshot::Sender<()>,
+    checker: JoinHandle<()>,
+    timer_shutdown_tx: oneshot::Sender<()>,
+    timer: JoinHandle<()>,
     tp: Vec<(oneshot::Sender<()>, JoinHandle<anyhow::Result<()>>)>,
+    pub submitter: submitter::Submitter,
 }
 
 impl Liquidation {
-    pub async fn new(config: config::Config, mp: SharedStateMap, tasks: usize) -> Self {
+    pub async fn new(
+        config: config::Config,
+        ctx: com::SharedBotContext,
+        mp: SharedStateMap,
+        tasks: usize,
+        dry_run: bool,
+    ) -> Self {
         let mut ts = tasks;
         if ts <= 0 {
             ts = 2;
         }
         let (shutdown_tx, mut shutdown_rx) = oneshot::channel::<()>();
-        let (task_ch_tx, task_ch_rx) = flume::bounded::<Pubkey>(ts);
+        let (timer_shutdown_tx, mut timer_shutdown_rx) = oneshot::channel::<()>();
         let (timer_ch_tx, timer_ch_rx) = flume::bounded::<Pubkey>(ts);
 
         // The position capital fee is charged every eight hours (fixed at 0:00, 8:00 and 16:00 GMT+0)
         let tmp = mp.clone();
-        tokio::spawn(async move {
+        let timer = tokio::spawn(async move {
             let next_run_time = time_to_next_run();
             let start = time::Instant::now() + time::Duration::from_secs(next_run_time as u64);
-            let mut interval = time::interval_at(start, time::Duration::from_secs(8 * 3600));
+            let mut interval =
+                time::interval_at(start, time::Duration::from_secs(FUNDING_PERIOD as u64));
             loop {
-                let i = interval.tick().await;
+                let i = tokio::select! {
+                    i = interval.tick() => i,
+                    _ = (&mut timer_shutdown_rx) => {
+                        info!("got shutdown signal, funding timer exit.");
+                        break;
+                    }
+                };
                 info!(
                     "The timer is awakened. The current time is: {} ,Run every: {:?}",
                     Utc::now().format("%Y-%m-%d %H:%M:%S"),
@@ -523,39 +1021,66 @@ impl Liquidation {
                 debug!("Complete a new round of funding... use time:{:?}", t);
             }
         });
-        // Keep checking position
+        // Keep checking position. Users with positions in a market are checked when its price moves,
+        // the full sweep of all users is a slow safety net.
         let lmp = mp.clone();
+        let lconfig = config.clone();
+        let queue = Arc::new(queue::RiskQueue::new(&config.risk_queue));
+        let lqueue = queue.clone();
+        let price_event_rx = mp.price_event_rx.clone();
 
-        tokio::spawn(async move {
+        let checker = tokio::spawn(async move {
             let mut count = 1u64;
+            let mut sweep = time::interval(time::Duration::from_secs(
+                lconfig.risk_queue.full_sweep_seconds.max(1),
+            ));
+            let mut guard = time::interval(PRICE_GUARD_INTERVAL);
             loop {
                 tokio::select! {
                     _ = (&mut shutdown_rx) => {
                         info!("got shutdown signal, user loop program exit.");
                         break;
                     }
-                    _=async{}=>{
+                    _ = guard.tick() => {
+                        lmp.check_price_guard(&lconfig);
+                    }
+                    _ = sweep.tick() => {
+                        if let Err(r) = lmp.check_sub_health() {
+                            warn!("pause liquidation: {}", r);
+                            continue;
+                        }
                         let now = time::Instant::now();
-                        time::sleep(time::Duration::from_secs(5)).await;
                         debug!("Start a new round of liquidation... count: {}",count);
-
-                        for v in &lmp.user {
-                            let pubkey = *v.key();
-                           match task_ch_tx.send(pubkey){
-                                Ok(())=>{}
-                                Err(e)=>{
-                                    debug!("task msg send error:{},exit send loop !",e);
-                                    break;
-                                }
-                           }
-                        }
+                        let users: Vec<Pubkey> = lmp.user.iter().map(|v| *v.key()).collect();
+                        queue_users(&lmp, &lqueue, users);
                         let t = now.elapsed();
                         count+=1;
                         debug!("Complete a new round of liquidation... use time: {:?},count: {}", t,count);
                     }
+                    r = price_event_rx.recv_async() => {
+                        match r {
+                            Ok(market_pubkey)=>{
+                                if let Err(r) = lmp.check_sub_health() {
+                                    debug!("pause liquidation: {}", r);
+                                    continue;
+                                }
+                                let users: Vec<Pubkey> = match lmp.market_idx_user.get(&market_pubkey) {
+                                    Some(u) => u.iter().map(|v| *v.key()).collect(),
+                                    None => Vec::new(),
+                                };
+                                debug!("price of market {} moved, check {} users",market_pubkey,users.len());
+                                queue_users(&lmp, &lqueue, users);
+                            }
+                            Err(e)=>{
+                                info!("price event recv error:{},exit!",e);
+                                break;
+                            }
+                        }
+                    }
                 }
             }
         });
+        let submitter = submitter::Submitter::new(config.clone(), ctx);
         let mut workers: Vec<(oneshot::Sender<()>, JoinHandle<anyhow::Result<()>>)> =
             Vec::with_capacity(ts);
         for _ in 0..ts {
@@ -564,8 +1089,10 @@ impl Liquidation {
             let (task_shutdown_tx, task_shutdown_rx) = oneshot::channel::<()>();
             let task = tokio::spawn(loop_position_by_user(
                 cfg,
+                submitter.clone(),
                 smp,
-                task_ch_rx.clone(),
+                dry_run,
+                queue.clone(),
                 timer_ch_rx.clone(),
                 task_shutdown_rx,
             ));
@@ -573,18 +1100,49 @@ impl Liquidation {
         }
         Self {
             shutdown_tx,
+            checker,
+            timer_shutdown_tx,
+            timer,
             tp: workers,
+            submitter,
         }
     }
+    // Stop queueing users and wait for the workers, then for the burst transactions in flight
+    // to be confirmed or expire, they are not sent again.
     pub async fn shutdown(self) {
         _ = self.shutdown_tx.send(());
-        // wait
+        _ = self.checker.await;
+        _ = self.timer_shutdown_tx.send(());
+        _ = self.timer.await;
         for v in self.tp {
             _ = v.0.send(());
-            // let _ = v.1.await;
+            _ = v.1.await;
         }
+        self.submitter.shutdown();
+        loop {
+            let n = self.submitter.in_flight_len();
+            if n == 0 {
+                break;
+            }
+            info!("wait for {} burst transactions in flight ...", n);
+            time::sleep(IN_FLIGHT_POLL_INTERVAL).await;
+        }
+        info!("liquidation shutdown!");
     }
 }
+// Queue the users for the liquidation workers by their last margin ratio,
+// the ones far from the burst rate are skipped until their next check is due.
+fn queue_users(mp: &StateMap, queue: &queue::RiskQueue, users: Vec<Pubkey>) {
+    let total = users.len();
+    let mut n = 0usize;
+    for u in users {
+        let ratio = mp.margin_rate_idx.get(&u).map(|r| *r);
+        if queue.push(u, ratio) {
+            n += 1;
+        }
+    }
+    debug!("queue {} of {} users, waiting: {}", n, total, queue.len());
+}
 // Return seconds
 fn time_to_next_run() -> i64 {
     let now = Utc::now().naive_utc();
@@ -605,8 +1163,10 @@ fn time_to_next_run() -> i64 {
 
 async fn loop_position_by_user(
     config: config::Config,
+    submitter: submitter::Submitter,
     mp: SharedStateMap,
-    task_rx: flume::Receiver<Pubkey>,
+    dry_run: bool,
+    queue: Arc<queue::RiskQueue>,
     timer_task_rx: flume::Receiver<Pubkey>,
     mut shutdown_rx: oneshot::Receiver<()>,
 ) -> anyhow::Result<()> {
@@ -618,36 +1178,31 @@ async fn loop_position_by_user(
                 info!("got shutdown signal, user position loop task exit.");
                 break;
             }
-            r = task_rx.recv_async() => {
-                // time::sleep(time::Duration::from_secs(10)).await;
-                match r {
-                    Ok(user_pubkey)=>{
-                        match mp.user.get(&user_pubkey){
-                            Some(v)=>{
-                                match mp.position.get(&user_pubkey) {
-                                    Some(ps) => {
-                                        match compute_position(&config,&user_pubkey,&v,&ps.value(),&mp.market,&mp.price_account,&mp.user_dynamic_idx,&mp.position_dynamic_idx){
-                                            Ok(())=>{
-                                                debug!("loop user {} success!",user_pubkey);
-                                            }
-                                            Err(e)=>{
-                                                debug!("loop user {} error: {}",user_pubkey,e);
-                                            }
-                                        }
-                                    }
-                                    None => {
-                                        debug!("loop user {} positions none,continue!",user_pubkey);
-                                    },
-                                }
-                            },
-                            None=>{
-                                debug!("Recv the user pubkey in task ,but get user data none!");
-                            }
-                        }
+            user_pubkey = queue.pop() => {
+                if let Err(r) = mp.check_sub_health() {
+                    debug!("skip user {}, pause liquidation: {}", user_pubkey, r);
+                    continue;
+                }
+                let burster = Burster::new(&config,&submitter,&mp.storage,dry_run);
+                let pending = submitter.user_pending(&user_pubkey);
+                // a close confirmed just now may not have reached the local accounts, plan the next one from the chain
+                let snapshot = if pending == Some(submitter::BurstStatus::Confirmed) {
+                    fetch_user_snapshot(submitter.ctx(),&mp,&user_pubkey).await
+                } else {
+                    mp.user_snapshot(&user_pubkey).ok_or_else(|| {
+                        com::CliError::Unknown(format!("user {} data or positions none", user_pubkey)).into()
+                    })
+                };
+                let close_in_flight = pending == Some(submitter::BurstStatus::InFlight);
+                match snapshot.and_then(|s| compute_position(&config,&burster,&mp,&s,close_in_flight)){
+                    Ok(())=>{
+                        debug!("loop user {} success!",user_pubkey);
                     }
                     Err(e)=>{
-                        info!("position loop task recv error:{},exit!",e);
-                        break;
+                        if !mp.user.contains_key(&user_pubkey) {
+                            queue.remove(&user_pubkey);
+                        }
+                        debug!("loop user {} error: {}",user_pubkey,e);
                     }
                 }
             }
@@ -658,7 +1213,7 @@ async fn loop_position_by_user(
                             Some(v)=>{
                                 match mp.position.get(&user_pubkey) {
                                     Some(ps) => {
-                                        match funding_rate_settlement(&config,&user_pubkey,&v,ps.value()){
+                                        match record_funding_charge(&config,&user_pubkey,&v,ps.value(),&mp.market,&mp.storage){
                                             Ok(())=>{
                                                 debug!("timer loop user {} success!",user_pubkey);
                                             }
@@ -691,307 +1246,306 @@ async fn loop_position_by_user(
     Ok(())
 }
 
-fn funding_rate_settlement(
+// Record the funding charge of the user for the current period. The charge is not settled
+// on chain, the program has no funding settlement instruction to submit.
+fn record_funding_charge(
     _config: &config::Config,
     user_pubkey: &Pubkey,
     _user_account: &user::UserAccount,
-    _position: &DmPosition,
-) -> anyhow::Result<()> {
-    debug!("Funding rate settlement: {}", user_pubkey);
-    // todo
-    // let client = com::Context::new_client(config);
-    Ok(())
-}
-
-fn compute_position(
-    config: &config::Config,
-    user_pubkey: &Pubkey,
-    user_account: &user::UserAccount,
-    position: &DmPosition,
+    positions: &DmPosition,
     market_mp: &DmMarket,
-    price_map: &DmPrice,
-    user_dynamic_idx_mp: &DmUserDynamicData,
-    position_dynamic_idx_mp: &DmPositionDynamicData,
+    storage: &storage::Storage,
 ) -> anyhow::Result<()> {
-    debug!("compute user's position: {}", user_pubkey);
-    let client = com::Context::new_client(config)?;
-    let data_full = compute_pl_all_full_position(
-        config,
-        &client,
-        user_pubkey,
-        user_account,
-        market_mp,
-        price_map,
-        position_dynamic_idx_mp,
-    )?;
-    let data_independent = compute_pl_all_independent_position(
-        &client,
-        user_pubkey,
-        position,
-        market_mp,
-        price_map,
-        position_dynamic_idx_mp,
-    )?;
-    let equity = data_full.equity + data_independent.equity + user_account.balance;
-    let profit = data_independent.profit + data_full.profit;
-    let data = UserDynamicData {
-        profit,
-        margin_percentage: equity / user_account.margin_total,
-        equity,
-        profit_rate: profit / user_account.margin_total,
+    debug!("Record funding charge: {}", user_pubkey);
+    let now = Utc::now().timestamp();
+    let mut record = FundingRecord {
+        user: *user_pubkey,
+        period: now - now % FUNDING_PERIOD,
+        full_fund: com::Money::ZERO,
+        independent_fund: com::Money::ZERO,
+        positions: Vec::with_capacity(positions.len()),
     };
-    user_dynamic_idx_mp.insert(*user_pubkey, data);
-    Ok(())
-}
-
-pub fn compute_pl_all_independent_position(
-    anchor_client: &anchor_client::Client,
-    user_pubkey: &Pubkey,
-    positions: &DmPosition,
-    market_mp: &DmMarket,
-    price_map: &DmPrice,
-    position_dynamic_idx_mp: &DmPositionDynamicData,
-) -> anyhow::Result<UserDynamicData> {
-    let mut data = UserDynamicData::default();
-
     for v in positions {
-        if v.position_type == position::PositionType::Full {
-            continue;
-        }
         match market_mp.get(&v.market_account) {
-            Some(market) => match price_map.get(&market.pyth_price_account) {
-                Some(price) => {
-                    let pl = v.get_pl_price(price.value());
-                    data.profit += pl;
-                    let total_pl =
-                        pl + market.get_position_fund(v.direction.clone(), v.get_fund_size());
-                    let equity = v.margin + total_pl;
-                    data.equity = equity;
-                    position_dynamic_idx_mp.insert(
-                        *v.key(),
-                        PositionDynamicData {
-                            profit_rate: pl / v.margin,
-                        },
-                    );
-                    if equity / v.margin < bcom::BURST_RATE {
-                        match client::burst_position(
-                            anchor_client,
-                            *user_pubkey,
-                            *market.key(),
-                            *v.key(),
-                            market.pyth_price_account,
-                            market.chianlink_price_account,
-                        ) {
-                            Ok(()) => {
-                                info!("burst position success! pubkey: {}", v.key());
-                            }
-                            Err(e) => {
-                                error!("burst position success error:{}", e);
-                                continue;
-                            }
-                        }
-                    }
-                }
-                None => {
-                    error!(
-                            "Cannot get price data, continue! position pubkey: {},market_pubkey: {},pyth_price_pubkey: {}",
-                            v.key(),
-                            v.market_account,
-                            market.pyth_price_account
-                        );
+            Some(market) => {
+                let fund = com::Money::from_chain(
+                    market.get_position_fund(v.direction.clone(), v.get_fund_size()),
+                );
+                let full = v.position_type == position::PositionType::Full;
+                if full {
+                    record.full_fund += fund;
+                } else {
+                    record.independent_fund += fund;
                 }
-            },
+                record.positions.push(FundingPosition {
+                    pubkey: *v.key(),
+                    market_account: v.market_account,
+                    full,
+                    fund,
+                });
+            }
             None => {
                 error!(
-                    "Cannot get market data , continue! position pubkey: {},market_pubkey: {}",
+                    "Cannot get market data for funding, continue! position pubkey: {},market_pubkey: {}",
                     v.key(),
                     v.market_account
                 );
             }
         }
     }
-    Ok(data)
+    if record.positions.is_empty() {
+        return Ok(());
+    }
+    // The bond program applies the fund to a position in ClosePosition, its instructions
+    // (InitializeVault, InitializeMarket, InitializeUserAccount, Deposit, OpenPosition,
+    // ClosePosition, Investment, Divestment) have no standalone funding settlement.
+    let keys = storage::Keys::new(storage::Prefix::Funding)
+        .add(user_pubkey.to_string())
+        .add(record.period.to_string());
+    storage.save_record(&keys, &record)?;
+    info!(
+        "funding charge recorded, not settled on chain! user: {},period: {},full fund: {},independent fund: {}",
+        user_pubkey, record.period, record.full_fund, record.independent_fund
+    );
+    Ok(())
 }
-#[derive(Debug, Eq, Ord, PartialEq, PartialOrd)]
-struct PositionSort {
-    pub offset: u32,
-    pub profit: i64,
-    pub direction: position::Direction,
-    pub margin: u64,
-    pub market_account: Option<Pubkey>,
+
+// A burst that would have been sent in dry run mode.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DryRunRecord {
+    pub user: Pubkey,
+    pub position: Pubkey,
+    pub market_account: Pubkey,
+    pub timestamp: i64,
+    pub equity_ratio: f64,
+    pub positions: Vec<risk::BurstPosition>,
 }
 
-// Floating P/L
-pub fn compute_pl_all_full_position(
-    config: &config::Config,
-    anchor_client: &anchor_client::Client,
-    user_pubkey: &Pubkey,
-    user_account_data: &user::UserAccount,
-    market_mp: &DmMarket,
-    price_map: &DmPrice,
-    position_dynamic_idx_mp: &DmPositionDynamicData,
-) -> anyhow::Result<UserDynamicData> {
-    let btc_price = price_map
-        .get(config.get_pyth_btc_pubkey())
-        .ok_or(com::CliError::PriceError("get none btc price".to_string()))?;
-    let eth_price = price_map
-        .get(config.get_pyth_eth_pubkey())
-        .ok_or(com::CliError::PriceError("get none eth price".to_string()))?;
-    let sol_price = price_map
-        .get(config.get_pyth_sol_pubkey())
-        .ok_or(com::CliError::PriceError("get none sol price".to_string()))?;
-
-    let headers = &user_account_data.open_full_position_headers;
-    let mut total_pl: f64 = 0.0;
-
-    let mut data = UserDynamicData::default();
-
-    let mut position_sort: Vec<PositionSort> = Vec::with_capacity(headers.len());
-
-    for header in headers.iter() {
-        let (position_pubkey, _pbump) = Pubkey::find_program_address(
-            &[
-                bcom::POSITION_ACCOUNT_SEED,
-                &user_account_data.authority.to_bytes(),
-                &user_pubkey.to_bytes(),
-                &header.position_seed_offset.to_string().as_bytes(),
-            ],
-            &com::id(),
-        );
-        let (fund_rate, market_pubkey, pl) = match header.market {
-            bcom::FullPositionMarket::BtcUsd => {
-                let pl = header.get_pl_price(btc_price.value());
-                data.profit += pl;
-                let market_account = bcom::FullPositionMarket::BtcUsd.to_pubkey().0;
-                let fund_rate = match market_mp.get(&market_account) {
-                    Some(v) => {
-                        v.get_position_fund(header.direction.clone(), header.get_fund_size())
-                    }
-                    None => {
-                        debug!("missing BTC/USD account data. full position compute continue");
-                        0.0
-                    }
-                };
-                (fund_rate, Some(market_account), pl)
-            }
+// Submit the burst transaction, or only log and record it in dry run mode.
+// burst returns false if the position is already pending and was not submitted again.
+pub struct Burster<'a> {
+    config: &'a config::Config,
+    submitter: &'a submitter::Submitter,
+    storage: &'a storage::Storage,
+    dry_run: bool,
+}
 
-            bcom::FullPositionMarket::EthUsd => {
-                let pl = header.get_pl_price(eth_price.value());
-                data.profit += pl;
-                let market_account = bcom::FullPositionMarket::EthUsd.to_pubkey().0;
-                let fund_rate = match market_mp.get(&market_account) {
-                    Some(v) => {
-                        v.get_position_fund(header.direction.clone(), header.get_fund_size())
-                    }
-                    None => {
-                        debug!("missing ETH/USD account data. full position compute continue");
-                        0.0
-                    }
-                };
-                (fund_rate, Some(market_account), pl)
-            }
+impl<'a> Burster<'a> {
+    pub fn new(
+        config: &'a config::Config,
+        submitter: &'a submitter::Submitter,
+        storage: &'a storage::Storage,
+        dry_run: bool,
+    ) -> Self {
+        Self {
+            config,
+            submitter,
+            storage,
+            dry_run,
+        }
+    }
 
-            bcom::FullPositionMarket::SolUsd => {
-                let pl = header.get_pl_price(sol_price.value());
-                data.profit += pl;
-                let market_account = bcom::FullPositionMarket::SolUsd.to_pubkey().0;
-                let fund_rate = match market_mp.get(&market_account) {
-                    Some(v) => {
-                        v.get_position_fund(header.direction.clone(), header.get_fund_size())
-                    }
-                    None => {
-                        debug!("missing SOL/USD account data. full position compute continue");
-                        0.0
-                    }
-                };
-                (fund_rate, Some(market_account), pl)
-            }
-            _ => (0.0, None, 0.0),
-        };
-        position_dynamic_idx_mp.insert(
-            position_pubkey,
-            PositionDynamicData {
-                profit_rate: pl / header.margin,
-            },
+    pub fn burst(
+        &self,
+        user_pubkey: &Pubkey,
+        market_pubkey: Pubkey,
+        market: &market::Market,
+        position_pubkey: Pubkey,
+        equity_ratio: f64,
+        positions: &[risk::BurstPosition],
+    ) -> anyhow::Result<bool> {
+        if !self.dry_run {
+            let keys = client::BurstAccounts {
+                user_account: *user_pubkey,
+                market_account: market_pubkey,
+                position_account: position_pubkey,
+                pyth_price_account: market.pyth_price_account,
+                chianlink_price_account: market.chianlink_price_account,
+            };
+            let max_unit_price = self
+                .config
+                .get_market_config(&market.pair)
+                .max_compute_unit_price;
+            return Ok(self.submitter.submit(keys, max_unit_price));
+        }
+        info!(
+            "dry run, burst position: {} of user: {}, equity ratio: {}",
+            position_pubkey, user_pubkey, equity_ratio
         );
-        let profit_and_fund_rate = pl + fund_rate;
-
-        position_sort.push(PositionSort {
-            profit: (profit_and_fund_rate * 100.0) as i64,
-            offset: header.position_seed_offset,
-            direction: header.direction,
-            margin: (header.margin * 100.0) as u64,
+        let record = DryRunRecord {
+            user: *user_pubkey,
+            position: position_pubkey,
             market_account: market_pubkey,
-        });
-        total_pl += profit_and_fund_rate
-    }
-    data.equity = total_pl;
-    let equity = user_account_data.balance + total_pl;
-
-    let mut margin_full_buy_total = user_account_data.margin_full_buy_total;
-    let mut margin_full_sell_total = user_account_data.margin_full_sell_total;
-
-    let margin_full_total = bcom::f64_round(margin_full_buy_total.max(margin_full_sell_total));
-    // Forced close
-    if (equity / margin_full_total) < bcom::BURST_RATE {
-        // sort
-        position_sort.sort_by(|a, b| b.profit.cmp(&a.profit).reverse());
-        for p in position_sort {
-            // start burst
-            match p.market_account {
-                Some(market_pubkey) => match market_mp.get(&market_pubkey) {
-                    Some(v) => {
-                        let (position_pubkey, _pbump) = Pubkey::find_program_address(
-                            &[
-                                bcom::POSITION_ACCOUNT_SEED,
-                                &user_account_data.authority.to_bytes(),
-                                &user_pubkey.to_bytes(),
-                                &p.offset.to_string().as_bytes(),
-                            ],
-                            &com::id(),
-                        );
+            timestamp: Utc::now().timestamp(),
+            equity_ratio,
+            positions: positions.to_vec(),
+        };
+        // one record per round, so the would-be liquidations can be audited
+        let keys = storage::Keys::new(storage::Prefix::DryRun)
+            .add(user_pubkey.to_string())
+            .add(position_pubkey.to_string())
+            .add(record.timestamp.to_string());
+        self.storage.save_record(&keys, &record)?;
+        Ok(true)
+    }
+}
 
-                        match client::burst_position(
-                            anchor_client,
-                            *user_pubkey,
-                            market_pubkey,
-                            position_pubkey,
-                            v.pyth_price_account,
-                            v.chianlink_price_account,
-                        ) {
-                            Ok(()) => {
-                                info!("burst position success! pubkey: {}", position_pubkey);
-                            }
-                            Err(e) => {
-                                error!("burst position success error:{}", e);
-                                continue;
-                            }
-                        }
-                    }
-                    None => {
-                        continue;
+// Fetch the user and its open positions from the chain.
+async fn fetch_user_snapshot(
+    ctx: &com::BotContext,
+    mp: &StateMap,
+    user_pubkey: &Pubkey,
+) -> anyhow::Result<risk::UserSnapshot> {
+    let mut keys = vec![*user_pubkey];
+    if let Some(ps) = mp.position.get(user_pubkey) {
+        keys.extend(ps.iter().map(|v| *v.key()));
+    }
+    let rpc = ctx.rpc();
+    let mut account = None;
+    let mut positions = Vec::new();
+    for chunk in keys.chunks(MAX_MULTIPLE_ACCOUNTS) {
+        let accounts = rpc
+            .get_multiple_accounts_with_commitment(chunk, ctx.commitment)
+            .await?
+            .value;
+        for (pubkey, a) in chunk.iter().zip(accounts) {
+            let a = match a {
+                Some(a) => a,
+                None => continue,
+            };
+            match State::from(&a) {
+                State::User(u) => account = Some(u),
+                State::Position(p) => {
+                    if p.position_status != position::PositionStatus::NormalClosing
+                        && p.position_status != position::PositionStatus::ForceClosing
+                    {
+                        positions.push((*pubkey, p));
                     }
-                },
-                None => {
-                    continue;
                 }
+                _ => {}
             }
+        }
+    }
+    let account = account.ok_or_else(|| {
+        com::CliError::Unknown(format!("user {} not found on chain", user_pubkey))
+    })?;
+    Ok(mp.snapshot_of(*user_pubkey, account, positions))
+}
 
-            match p.direction {
-                position::Direction::Buy => {
-                    margin_full_buy_total -= (p.margin / 100) as f64;
-                }
-                position::Direction::Sell => {
-                    margin_full_sell_total -= (p.margin / 100) as f64;
-                }
+// Evaluate the risk of a user and act on it.
+// The full positions are closed one at a time, none is closed while a burst of the user is
+// in flight as it changes the balance and the margins they are planned on.
+fn compute_position(
+    config: &config::Config,
+    burster: &Burster,
+    mp: &StateMap,
+    snapshot: &risk::UserSnapshot,
+    close_in_flight: bool,
+) -> anyhow::Result<()> {
+    let user_pubkey = &snapshot.user;
+    debug!("compute user's position: {}", user_pubkey);
+    let ev = risk::evaluate(snapshot);
+    for w in &ev.warnings {
+        warn!("{}", w);
+    }
+    for (pubkey, data) in ev.positions {
+        mp.position_dynamic_idx.insert(pubkey, data);
+    }
+    mp.user_dynamic_idx.insert(*user_pubkey, ev.user.clone());
+    // a burst is decided on each position, so the queue orders the users by the lowest rate
+    match ev.min_margin_rate() {
+        Some(r) => {
+            mp.margin_rate_idx.insert(*user_pubkey, r);
+        }
+        None => {
+            mp.margin_rate_idx.remove(user_pubkey);
+        }
+    }
+    send_margin_calls(config, mp, user_pubkey, &ev);
+    for b in ev.bursts {
+        if b.full && close_in_flight {
+            debug!(
+                "a burst of user {} is in flight, wait for it to close full position {}",
+                user_pubkey, b.position
+            );
+            continue;
+        }
+        let quote = match snapshot.quotes.get(&b.market_account) {
+            Some(q) => q,
+            None => continue,
+        };
+        match burster.burst(
+            user_pubkey,
+            b.market_account,
+            &quote.market,
+            b.position,
+            b.equity_ratio,
+            &b.positions,
+        ) {
+            Ok(true) => {
+                debug!("burst position submitted! pubkey: {}", b.position);
             }
-            if ((equity - (p.profit / 100) as f64)
-                / margin_full_buy_total.max(margin_full_sell_total))
-                > bcom::BURST_RATE
-            {
-                break;
+            Ok(false) => {
+                debug!("burst position is pending, skipped! pubkey: {}", b.position);
             }
+            Err(e) => {
+                error!("burst position error:{}", e);
+            }
+        }
+    }
+    Ok(())
+}
+
+// Send a margin call when a user or position falls under a deeper level than the last call.
+fn send_margin_calls(
+    config: &config::Config,
+    mp: &StateMap,
+    user_pubkey: &Pubkey,
+    ev: &risk::Evaluation,
+) {
+    // the user has no full position left, its next call starts from the first level
+    if ev.full_margin_rate.is_none() {
+        mp.margin_call_level.remove(user_pubkey);
+    }
+    let levels = config.get_margin_call_levels();
+    if levels.is_empty() {
+        return;
+    }
+    let targets = ev
+        .full_margin_rate
+        .map(|r| (risk::MarginCallTarget::User, *user_pubkey, r))
+        .into_iter()
+        .chain(
+            ev.margin_rates
+                .iter()
+                .map(|(k, r)| (risk::MarginCallTarget::Position, *k, *r)),
+        );
+    for (target, pubkey, margin_rate) in targets {
+        let level = risk::margin_call_level(margin_rate, &levels);
+        let last = mp.margin_call_level.get(&pubkey).map(|v| *v).unwrap_or(0);
+        if level == 0 {
+            mp.margin_call_level.remove(&pubkey);
+            continue;
+        }
+        mp.margin_call_level.insert(pubkey, level);
+        if level <= last {
+            continue;
+        }
+        let call = risk::MarginCall {
+            target,
+            user: *user_pubkey,
+            pubkey,
+            level,
+            threshold: levels[level - 1],
+            margin_rate,
+            timestamp: Utc::now().timestamp(),
+        };
+        info!(
+            "margin call of {:?} {}, user: {},level: {},margin rate: {}",
+            target, pubkey, user_pubkey, level, margin_rate
+        );
+        if let Err(e) = mp.margin_call_tx.send(call) {
+            debug!("margin call has no receiver: {}", e);
         }
     }
-    Ok(data)
 }
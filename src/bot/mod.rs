@@ -1,5 +1,11 @@
 pub mod app;
+pub mod channel;
+#[cfg(feature = "geyser")]
+pub mod geyser;
 pub mod machine;
 pub mod price;
+pub mod queue;
+pub mod risk;
 pub mod storage;
+pub mod submitter;
 pub mod sub;
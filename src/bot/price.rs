@@ -1,23 +1,339 @@
-use crate::com;
+use crate::{com, config};
 use anchor_client::solana_sdk::{account::Account, pubkey::Pubkey};
 use bond::com as bcom;
+use chrono::Utc;
 use pyth_sdk_solana::{load_price_feed_from_account, Price, PriceFeed};
+use serde::{Deserialize, Serialize};
+use std::fmt;
 
-pub fn get_price(pubkey: &Pubkey, account: &mut Account) -> anyhow::Result<f64> {
-    get_price_from_pyth(pubkey, account)
-    // todo ,if error then get price from chainlink
+// Layout of the chainlink store transmissions account, offsets include the 8 bytes discriminator.
+const CHAINLINK_HEADER_SIZE: usize = 192;
+const CHAINLINK_TRANSMISSION_SIZE: usize = 48;
+const CHAINLINK_DECIMALS_OFFSET: usize = 138;
+const CHAINLINK_LIVE_LENGTH_OFFSET: usize = 148;
+const CHAINLINK_LIVE_CURSOR_OFFSET: usize = 152;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PriceSource {
+    Pyth,
+    Chainlink,
+}
+
+impl fmt::Display for PriceSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let t = match *self {
+            Self::Pyth => "pyth",
+            Self::Chainlink => "chainlink",
+        };
+        write!(f, "{}", t)
+    }
 }
 
-pub fn get_price_from_pyth(pubkey: &Pubkey, account: &mut Account) -> anyhow::Result<f64> {
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PriceData {
+    pub price: f64,
+    // confidence interval, in the same unit as price
+    pub conf: f64,
+    pub publish_time: i64,
+    pub source: PriceSource,
+}
+
+impl PriceData {
+    pub fn is_stale(&self, now: i64, guard: &config::MarketConfig) -> bool {
+        now - self.publish_time > guard.max_stale_seconds
+    }
+
+    // Return the reason if the price can not be trusted.
+    pub fn check(&self, now: i64, guard: &config::MarketConfig) -> Result<(), String> {
+        if self.is_stale(now, guard) {
+            return Err(format!(
+                "{} price is stale, publish time: {}",
+                self.source, self.publish_time
+            ));
+        }
+        if self.price <= 0.0 || self.conf / self.price > guard.max_conf_ratio {
+            return Err(format!(
+                "{} price confidence is too wide, price: {},conf: {}",
+                self.source, self.price, self.conf
+            ));
+        }
+        Ok(())
+    }
+}
+
+// Get the price from pyth, fall back to the latest chainlink price when pyth errors or is untrusted.
+pub fn get_price(
+    pubkey: &Pubkey,
+    account: &mut Account,
+    fallback: Option<PriceData>,
+    guard: &config::MarketConfig,
+) -> anyhow::Result<PriceData> {
+    let now = Utc::now().timestamp();
+    let err = match get_price_from_pyth(pubkey, account) {
+        Ok(p) => match p.check(now, guard) {
+            Ok(()) => return Ok(p),
+            Err(e) => com::CliError::PriceError(e),
+        },
+        Err(e) => com::CliError::PriceError(e.to_string()),
+    };
+    match fallback {
+        Some(p) if p.check(now, guard).is_ok() => Ok(p),
+        _ => Err(err.into()),
+    }
+}
+
+pub fn get_price_from_pyth(pubkey: &Pubkey, account: &mut Account) -> anyhow::Result<PriceData> {
     let price_feed: PriceFeed = load_price_feed_from_account(pubkey, account)
         .map_err(|e| com::CliError::PriceError(e.to_string()))?;
     let current_price: Price = price_feed
         .get_current_price()
         .ok_or(com::CliError::PriceError("price none".to_string()))?;
 
-    let price = bcom::f64_round(
-        (current_price.price as f64 / 10u64.pow(current_price.expo.abs() as u32) as f64)
-            * bcom::DECIMALS,
-    );
-    Ok(price)
+    let scale = 10u64.pow(current_price.expo.unsigned_abs()) as f64;
+    let price = bcom::f64_round((current_price.price as f64 / scale) * bcom::DECIMALS);
+    let conf = bcom::f64_round((current_price.conf as f64 / scale) * bcom::DECIMALS);
+    Ok(PriceData {
+        price,
+        conf,
+        publish_time: price_feed.publish_time,
+        source: PriceSource::Pyth,
+    })
+}
+
+pub fn get_price_from_chainlink(account: &Account) -> anyhow::Result<PriceData> {
+    let data = account.data.as_slice();
+    if data.len() < 8 + CHAINLINK_HEADER_SIZE + CHAINLINK_TRANSMISSION_SIZE {
+        return Err(com::CliError::PriceError(format!(
+            "invalid chainlink account data len: {}",
+            data.len()
+        ))
+        .into());
+    }
+    let decimals = data[CHAINLINK_DECIMALS_OFFSET];
+    let live_length = read_u32(data, CHAINLINK_LIVE_LENGTH_OFFSET);
+    let live_cursor = read_u32(data, CHAINLINK_LIVE_CURSOR_OFFSET);
+    if live_length == 0 {
+        return Err(com::CliError::PriceError("chainlink feed has no round".to_string()).into());
+    }
+    // The cursor points to the next round to write, in u64 so malformed data can not overflow.
+    let idx = ((live_cursor as u64 + live_length as u64 - 1) % live_length as u64) as usize;
+    let start = 8 + CHAINLINK_HEADER_SIZE + idx * CHAINLINK_TRANSMISSION_SIZE;
+    if data.len() < start + CHAINLINK_TRANSMISSION_SIZE {
+        return Err(
+            com::CliError::PriceError("chainlink round out of account data".to_string()).into(),
+        );
+    }
+    let timestamp = read_u32(data, start + 8);
+    let mut answer = [0u8; 16];
+    answer.copy_from_slice(&data[start + 16..start + 32]);
+    let answer = i128::from_le_bytes(answer);
+    if timestamp == 0 || answer <= 0 {
+        return Err(com::CliError::PriceError("chainlink price none".to_string()).into());
+    }
+    let scale = 10u64.checked_pow(decimals as u32).ok_or_else(|| {
+        com::CliError::PriceError(format!("invalid chainlink decimals: {}", decimals))
+    })?;
+    let price = bcom::f64_round((answer as f64 / scale as f64) * bcom::DECIMALS);
+    Ok(PriceData {
+        price,
+        conf: 0.0,
+        publish_time: timestamp as i64,
+        source: PriceSource::Chainlink,
+    })
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    let mut b = [0u8; 4];
+    b.copy_from_slice(&data[offset..offset + 4]);
+    u32::from_le_bytes(b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A store transmissions account in the layout above, with live_length rounds.
+    struct Feed {
+        decimals: u8,
+        live_length: u32,
+        live_cursor: u32,
+        // (round index, timestamp, answer)
+        rounds: Vec<(usize, u32, i128)>,
+    }
+
+    impl Feed {
+        fn account(&self) -> Account {
+            let rounds = (self.live_length as usize).min(8);
+            let mut data =
+                vec![0u8; 8 + CHAINLINK_HEADER_SIZE + rounds * CHAINLINK_TRANSMISSION_SIZE];
+            data[CHAINLINK_DECIMALS_OFFSET] = self.decimals;
+            data[CHAINLINK_LIVE_LENGTH_OFFSET..CHAINLINK_LIVE_LENGTH_OFFSET + 4]
+                .copy_from_slice(&self.live_length.to_le_bytes());
+            data[CHAINLINK_LIVE_CURSOR_OFFSET..CHAINLINK_LIVE_CURSOR_OFFSET + 4]
+                .copy_from_slice(&self.live_cursor.to_le_bytes());
+            for (i, timestamp, answer) in &self.rounds {
+                let start = 8 + CHAINLINK_HEADER_SIZE + i * CHAINLINK_TRANSMISSION_SIZE;
+                data[start + 8..start + 12].copy_from_slice(&timestamp.to_le_bytes());
+                data[start + 16..start + 32].copy_from_slice(&answer.to_le_bytes());
+            }
+            Account {
+                lamports: 1,
+                data,
+                owner: Pubkey::new_unique(),
+                executable: false,
+                rent_epoch: 0,
+            }
+        }
+    }
+
+    // Write the fields of the store program Transmissions account in declaration order, so
+    // the offsets follow from the upstream struct and not from the constants of the decoder.
+    #[derive(Default)]
+    struct StoreWriter {
+        data: Vec<u8>,
+    }
+
+    impl StoreWriter {
+        fn put(&mut self, b: &[u8]) -> &mut Self {
+            self.data.extend_from_slice(b);
+            self
+        }
+
+        fn pad_to(&mut self, len: usize) -> &mut Self {
+            self.data.resize(len, 0);
+            self
+        }
+    }
+
+    // A BTC / USD feed with 8 decimals and two live rounds, the cursor on the third slot.
+    fn store_account() -> Account {
+        let mut w = StoreWriter::default();
+        let mut description = [0u8; 32];
+        description[..9].copy_from_slice(b"BTC / USD");
+        // anchor discriminator of Transmissions
+        w.put(&[96, 179, 69, 66, 128, 129, 73, 117])
+            // version, state
+            .put(&[2, 1])
+            // owner, proposed_owner, writer
+            .put(&[7; 32])
+            .put(&[0; 32])
+            .put(&[9; 32])
+            .put(&description)
+            // decimals
+            .put(&[8])
+            // flagging_threshold, latest_round_id
+            .put(&1_000u32.to_le_bytes())
+            .put(&42u32.to_le_bytes())
+            // granularity
+            .put(&[30])
+            // live_length, live_cursor, historical_cursor
+            .put(&3u32.to_le_bytes())
+            .put(&2u32.to_le_bytes())
+            .put(&0u32.to_le_bytes())
+            // the header is 192 bytes after the discriminator
+            .pad_to(8 + 192);
+        // the live rounds: slot, timestamp, padding, answer, padding
+        for (slot, timestamp, answer) in [
+            (170_000_000u64, 1_670_000_000u32, 1_700_012_345_678i128),
+            (170_000_150, 1_670_000_060, 1_701_000_000_000),
+        ] {
+            w.put(&slot.to_le_bytes())
+                .put(&timestamp.to_le_bytes())
+                .put(&[0; 4])
+                .put(&answer.to_le_bytes())
+                .put(&[0; 16]);
+        }
+        let len = w.data.len() + 48;
+        w.pad_to(len);
+        Account {
+            lamports: 1,
+            data: w.data,
+            owner: Pubkey::new_unique(),
+            executable: false,
+            rent_epoch: 0,
+        }
+    }
+
+    #[test]
+    fn decode_store_layout() {
+        let account = store_account();
+        assert_eq!(account.data.len(), 8 + 192 + 3 * 48);
+        let p = get_price_from_chainlink(&account).unwrap();
+        assert_eq!(p.publish_time, 1_670_000_060);
+        assert_eq!(p.price, bcom::f64_round(17010.0 * bcom::DECIMALS));
+        assert_eq!(p.source, PriceSource::Chainlink);
+    }
+
+    #[test]
+    fn decode_latest_round() {
+        let feed = Feed {
+            decimals: 8,
+            live_length: 3,
+            live_cursor: 2,
+            rounds: vec![(0, 100, 1_900_000_000_000), (1, 200, 2_000_000_000_000)],
+        };
+        let p = get_price_from_chainlink(&feed.account()).unwrap();
+        assert_eq!(p.publish_time, 200);
+        assert_eq!(p.price, bcom::f64_round(20000.0 * bcom::DECIMALS));
+        assert_eq!(p.source, PriceSource::Chainlink);
+    }
+
+    #[test]
+    fn decode_cursor_wraps_to_last_round() {
+        let feed = Feed {
+            decimals: 8,
+            live_length: 3,
+            live_cursor: 0,
+            rounds: vec![(0, 100, 1_900_000_000_000), (2, 300, 2_100_000_000_000)],
+        };
+        let p = get_price_from_chainlink(&feed.account()).unwrap();
+        assert_eq!(p.publish_time, 300);
+        assert_eq!(p.price, bcom::f64_round(21000.0 * bcom::DECIMALS));
+    }
+
+    #[test]
+    fn decode_rejects_malformed_data() {
+        let cases = vec![
+            // no round
+            Feed {
+                decimals: 8,
+                live_length: 0,
+                live_cursor: 0,
+                rounds: vec![],
+            },
+            // cursor and length overflow u32, the round is out of the data
+            Feed {
+                decimals: 8,
+                live_length: u32::MAX,
+                live_cursor: u32::MAX,
+                rounds: vec![],
+            },
+            // decimals overflow the scale
+            Feed {
+                decimals: 40,
+                live_length: 1,
+                live_cursor: 1,
+                rounds: vec![(0, 100, 1)],
+            },
+            // empty round
+            Feed {
+                decimals: 8,
+                live_length: 1,
+                live_cursor: 1,
+                rounds: vec![],
+            },
+        ];
+        for feed in cases {
+            assert!(get_price_from_chainlink(&feed.account()).is_err());
+        }
+        let mut short = Feed {
+            decimals: 8,
+            live_length: 1,
+            live_cursor: 1,
+            rounds: vec![(0, 100, 1)],
+        }
+        .account();
+        short.data.truncate(8 + CHAINLINK_HEADER_SIZE);
+        assert!(get_price_from_chainlink(&short).is_err());
+    }
 }
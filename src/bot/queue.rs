@@ -0,0 +1,217 @@
+use crate::config;
+use anchor_client::solana_sdk::pubkey::Pubkey;
+use bond::com as bcom;
+use dashmap::{DashMap, DashSet};
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::sync::Mutex;
+use tokio::sync::Notify;
+use tokio::time::{Duration, Instant};
+
+// Users whose margin ratio is below NEAR_RATE * BURST_RATE are checked on every request,
+// the others wait at least the interval of their tier, see config::RiskQueueConfig.
+const NEAR_RATE: f64 = 2.0;
+const MIDDLE_RATE: f64 = 4.0;
+
+struct Entry {
+    ratio: f64,
+    seq: u64,
+    user: Pubkey,
+}
+
+impl PartialEq for Entry {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Entry {}
+
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// The lowest margin ratio is the greatest, then the earliest pushed.
+impl Ord for Entry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .ratio
+            .partial_cmp(&self.ratio)
+            .unwrap_or(Ordering::Equal)
+            .then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+struct Heap {
+    entries: BinaryHeap<Entry>,
+    seq: u64,
+}
+
+// Users waiting for the liquidation workers, the ones closest to the burst rate come first.
+pub struct RiskQueue {
+    heap: Mutex<Heap>,
+    queued: DashSet<Pubkey>,
+    last_checked: DashMap<Pubkey, Instant>,
+    notify: Notify,
+    middle_interval: Duration,
+    far_interval: Duration,
+}
+
+impl RiskQueue {
+    pub fn new(config: &config::RiskQueueConfig) -> Self {
+        Self {
+            heap: Mutex::new(Heap {
+                entries: BinaryHeap::new(),
+                seq: 0,
+            }),
+            queued: DashSet::new(),
+            last_checked: DashMap::new(),
+            notify: Notify::new(),
+            middle_interval: Duration::from_secs(config.middle_interval_seconds),
+            far_interval: Duration::from_secs(config.far_interval_seconds),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.queued.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queued.is_empty()
+    }
+
+    // Push a user with the lowest margin rate of its positions, None if it has not been computed yet.
+    // Return false if the user is already queued or not due for a check.
+    pub fn push(&self, user: Pubkey, ratio: Option<f64>) -> bool {
+        let ratio = match ratio {
+            Some(r) if r.is_nan() => f64::MAX,
+            Some(r) => r,
+            None => f64::MIN,
+        };
+        if !self.is_due(&user, ratio) || !self.queued.insert(user) {
+            return false;
+        }
+        {
+            let mut heap = self.heap.lock().unwrap();
+            heap.seq += 1;
+            let seq = heap.seq;
+            heap.entries.push(Entry { ratio, seq, user });
+        }
+        self.notify.notify_one();
+        true
+    }
+
+    // Wait for the user with the highest risk.
+    pub async fn pop(&self) -> Pubkey {
+        loop {
+            let entry = self.heap.lock().unwrap().entries.pop();
+            if let Some(e) = entry {
+                self.queued.remove(&e.user);
+                self.last_checked.insert(e.user, Instant::now());
+                return e.user;
+            }
+            self.notify.notified().await;
+        }
+    }
+
+    pub fn remove(&self, user: &Pubkey) {
+        self.last_checked.remove(user);
+    }
+
+    fn is_due(&self, user: &Pubkey, ratio: f64) -> bool {
+        if ratio < bcom::BURST_RATE * NEAR_RATE {
+            return true;
+        }
+        let interval = if ratio < bcom::BURST_RATE * MIDDLE_RATE {
+            self.middle_interval
+        } else {
+            self.far_interval
+        };
+        match self.last_checked.get(user) {
+            Some(t) => t.elapsed() >= interval,
+            None => true,
+        }
+    }
+}
+
+impl Default for RiskQueue {
+    fn default() -> Self {
+        Self::new(&config::RiskQueueConfig::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queue(middle_interval_seconds: u64, far_interval_seconds: u64) -> RiskQueue {
+        RiskQueue::new(&config::RiskQueueConfig {
+            middle_interval_seconds,
+            far_interval_seconds,
+            full_sweep_seconds: 30,
+        })
+    }
+
+    #[tokio::test]
+    async fn lowest_margin_rate_pops_first() {
+        let q = RiskQueue::default();
+        let far = Pubkey::new_unique();
+        let near = Pubkey::new_unique();
+        let near_later = Pubkey::new_unique();
+        let unknown = Pubkey::new_unique();
+        let nan = Pubkey::new_unique();
+        assert!(q.push(far, Some(bcom::BURST_RATE * 10.0)));
+        assert!(q.push(nan, Some(f64::NAN)));
+        assert!(q.push(near, Some(bcom::BURST_RATE * 1.1)));
+        assert!(q.push(near_later, Some(bcom::BURST_RATE * 1.1)));
+        assert!(q.push(unknown, None));
+        assert!(!q.push(near, Some(bcom::BURST_RATE)));
+        assert_eq!(q.len(), 5);
+
+        for user in [unknown, near, near_later, far, nan] {
+            assert_eq!(q.pop().await, user);
+        }
+        assert!(q.is_empty());
+    }
+
+    #[tokio::test]
+    async fn tiers_wait_their_interval() {
+        let q = queue(3600, 3600);
+        let near = Pubkey::new_unique();
+        let middle = Pubkey::new_unique();
+        let far = Pubkey::new_unique();
+        let near_rate = Some(bcom::BURST_RATE * (NEAR_RATE - 0.1));
+        let middle_rate = Some(bcom::BURST_RATE * (MIDDLE_RATE - 0.1));
+        let far_rate = Some(bcom::BURST_RATE * MIDDLE_RATE);
+        for (user, rate) in [(near, near_rate), (middle, middle_rate), (far, far_rate)] {
+            assert!(q.push(user, rate));
+            assert_eq!(q.pop().await, user);
+        }
+        assert!(q.push(near, near_rate));
+        assert!(!q.push(middle, middle_rate));
+        assert!(!q.push(far, far_rate));
+        // a user falling into the near tier is checked at once
+        assert!(q.push(far, near_rate));
+
+        // a removed user is checked again on the next push
+        q.remove(&middle);
+        assert!(q.push(middle, middle_rate));
+    }
+
+    #[tokio::test]
+    async fn due_tiers_are_pushed_again() {
+        let q = queue(0, 3600);
+        let middle = Pubkey::new_unique();
+        let far = Pubkey::new_unique();
+        let middle_rate = Some(bcom::BURST_RATE * (MIDDLE_RATE - 0.1));
+        let far_rate = Some(bcom::BURST_RATE * MIDDLE_RATE);
+        for (user, rate) in [(middle, middle_rate), (far, far_rate)] {
+            assert!(q.push(user, rate));
+            assert_eq!(q.pop().await, user);
+        }
+        assert!(q.push(middle, middle_rate));
+        assert!(!q.push(far, far_rate));
+    }
+}
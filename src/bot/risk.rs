@@ -0,0 +1,894 @@
+use crate::com::{self, Money};
+use anchor_client::solana_sdk::pubkey::Pubkey;
+use bond::com as bcom;
+use bond::state::{market, position, user};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserDynamicData {
+    pub profit: Money,
+    pub margin_percentage: f64,
+    pub equity: Money,
+    pub profit_rate: f64,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PositionDynamicData {
+    pub profit_rate: f64,
+}
+
+impl Default for UserDynamicData {
+    fn default() -> Self {
+        UserDynamicData {
+            profit: Money::ZERO,
+            margin_percentage: 0.0,
+            equity: Money::ZERO,
+            profit_rate: 0.0,
+        }
+    }
+}
+impl Default for PositionDynamicData {
+    fn default() -> Self {
+        PositionDynamicData { profit_rate: 0.0 }
+    }
+}
+
+// A position considered by a burst, with the price and profit it was valued at.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BurstPosition {
+    pub pubkey: Pubkey,
+    pub market_account: Pubkey,
+    pub price: Money,
+    pub profit: Money,
+}
+
+// The market and its latest price, halted is the reason the price is not trusted.
+pub struct MarketQuote {
+    pub market: market::Market,
+    pub price: market::Price,
+    pub halted: Option<String>,
+}
+
+// A copy of everything the risk of a user depends on.
+pub struct UserSnapshot {
+    pub user: Pubkey,
+    pub account: user::UserAccount,
+    // key is position account
+    pub positions: Vec<(Pubkey, position::Position)>,
+    // key is market account
+    pub quotes: HashMap<Pubkey, MarketQuote>,
+}
+
+// A position to close.
+#[derive(Debug, Clone)]
+pub struct BurstDecision {
+    pub position: Pubkey,
+    pub market_account: Pubkey,
+    // a full position, closed one at a time
+    pub full: bool,
+    pub equity_ratio: f64,
+    pub positions: Vec<BurstPosition>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Evaluation {
+    pub user: UserDynamicData,
+    // key is position account
+    pub positions: Vec<(Pubkey, PositionDynamicData)>,
+    pub bursts: Vec<BurstDecision>,
+    // equity / margin of the independent positions, key is position account
+    pub margin_rates: Vec<(Pubkey, f64)>,
+    // equity / max(full buy margin, full sell margin), None if the user has no full position
+    pub full_margin_rate: Option<f64>,
+    // what could not be evaluated or burst, for the executor to log
+    pub warnings: Vec<String>,
+}
+
+impl Evaluation {
+    // The lowest margin rate a burst is decided on, None if the user has no position valued.
+    pub fn min_margin_rate(&self) -> Option<f64> {
+        self.margin_rates
+            .iter()
+            .map(|(_, r)| *r)
+            .chain(self.full_margin_rate)
+            .reduce(f64::min)
+    }
+}
+
+// The price data a position is valued at.
+#[derive(Debug, Clone)]
+pub struct Valuation {
+    pub pl: Money,
+    // funding fee
+    pub fund: Money,
+    pub price: Money,
+    pub pair: String,
+    // why the price of the market is not trusted
+    pub halted: Option<String>,
+}
+
+// A position of a user, value is None if its market or price is missing.
+#[derive(Debug, Clone)]
+pub struct ValuedPosition {
+    pub pubkey: Pubkey,
+    pub market_account: Pubkey,
+    pub direction: position::Direction,
+    pub margin: Money,
+    pub value: Option<Valuation>,
+}
+
+// A user with all its positions valued at the latest prices.
+#[derive(Debug, Clone)]
+pub struct ValuedUser {
+    pub user: Pubkey,
+    pub balance: Money,
+    pub margin_total: Money,
+    pub margin_full_buy_total: Money,
+    pub margin_full_sell_total: Money,
+    pub independent: Vec<ValuedPosition>,
+    pub full: Vec<ValuedPosition>,
+}
+
+// Compute the P/L of all the positions of a user and the positions to burst.
+pub fn evaluate(s: &UserSnapshot) -> Evaluation {
+    evaluate_valued(&value_user(s))
+}
+
+// Value every position of a user at the latest price of its market.
+pub fn value_user(s: &UserSnapshot) -> ValuedUser {
+    let account = &s.account;
+    let headers = &account.open_full_position_headers;
+
+    let mut full = Vec::with_capacity(headers.len());
+    for header in headers.iter() {
+        let (position_pubkey, _pbump) = Pubkey::find_program_address(
+            &[
+                bcom::POSITION_ACCOUNT_SEED,
+                &account.authority.to_bytes(),
+                &s.user.to_bytes(),
+                &header.position_seed_offset.to_string().as_bytes(),
+            ],
+            &com::id(),
+        );
+        let market_pubkey = header.market.to_pubkey().0;
+        let value = s.quotes.get(&market_pubkey).map(|quote| Valuation {
+            pl: Money::from_chain(header.get_pl_price(&quote.price)),
+            fund: Money::from_chain(
+                quote
+                    .market
+                    .get_position_fund(header.direction.clone(), header.get_fund_size()),
+            ),
+            price: Money::from_chain(quote.price.real_price),
+            pair: quote.market.pair.to_string(),
+            halted: quote.halted.clone(),
+        });
+        full.push(ValuedPosition {
+            pubkey: position_pubkey,
+            market_account: market_pubkey,
+            direction: header.direction,
+            margin: Money::from_chain(header.margin),
+            value,
+        });
+    }
+
+    let mut independent = Vec::new();
+    for (pubkey, v) in &s.positions {
+        if v.position_type == position::PositionType::Full {
+            continue;
+        }
+        let value = s.quotes.get(&v.market_account).map(|quote| Valuation {
+            pl: Money::from_chain(v.get_pl_price(&quote.price)),
+            fund: Money::from_chain(
+                quote
+                    .market
+                    .get_position_fund(v.direction.clone(), v.get_fund_size()),
+            ),
+            price: Money::from_chain(quote.price.real_price),
+            pair: quote.market.pair.to_string(),
+            halted: quote.halted.clone(),
+        });
+        independent.push(ValuedPosition {
+            pubkey: *pubkey,
+            market_account: v.market_account,
+            direction: v.direction,
+            margin: Money::from_chain(v.margin),
+            value,
+        });
+    }
+
+    ValuedUser {
+        user: s.user,
+        balance: Money::from_chain(account.balance),
+        margin_total: Money::from_chain(account.margin_total),
+        margin_full_buy_total: Money::from_chain(account.margin_full_buy_total),
+        margin_full_sell_total: Money::from_chain(account.margin_full_sell_total),
+        independent,
+        full,
+    }
+}
+
+// Compute the P/L and the positions to burst of a valued user.
+pub fn evaluate_valued(u: &ValuedUser) -> Evaluation {
+    let mut ev = Evaluation::default();
+    let data_full = evaluate_full_position(u, &mut ev);
+    let data_independent = evaluate_independent_position(u, &mut ev);
+    let equity = data_full.equity + data_independent.equity + u.balance;
+    let profit = data_independent.profit + data_full.profit;
+    ev.user = UserDynamicData {
+        profit,
+        margin_percentage: equity.rate(u.margin_total),
+        equity,
+        profit_rate: profit.rate(u.margin_total),
+    };
+    ev
+}
+
+fn evaluate_independent_position(u: &ValuedUser, ev: &mut Evaluation) -> UserDynamicData {
+    let mut data = UserDynamicData::default();
+
+    for p in &u.independent {
+        let value = match &p.value {
+            Some(v) => v,
+            None => {
+                ev.warnings.push(format!(
+                    "Cannot get market or price data, continue! position pubkey: {},market_pubkey: {}",
+                    p.pubkey, p.market_account
+                ));
+                continue;
+            }
+        };
+        data.profit += value.pl;
+        let equity = p.margin + value.pl + value.fund;
+        data.equity += equity;
+        ev.positions.push((
+            p.pubkey,
+            PositionDynamicData {
+                profit_rate: value.pl.rate(p.margin),
+            },
+        ));
+        ev.margin_rates.push((p.pubkey, equity.rate(p.margin)));
+        if equity.is_below_rate(p.margin, bcom::BURST_RATE) {
+            if let Some(r) = &value.halted {
+                ev.warnings.push(format!(
+                    "market {} is halted, skip burst position {}: {}",
+                    value.pair, p.pubkey, r
+                ));
+                continue;
+            }
+            ev.bursts.push(BurstDecision {
+                position: p.pubkey,
+                market_account: p.market_account,
+                full: false,
+                equity_ratio: equity.rate(p.margin),
+                positions: vec![BurstPosition {
+                    pubkey: p.pubkey,
+                    market_account: p.market_account,
+                    price: value.price,
+                    profit: value.pl,
+                }],
+            });
+        }
+    }
+    data
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MarginCallTarget {
+    // the full positions of a user
+    User,
+    // an independent position
+    Position,
+}
+
+// Sent when a user or position falls under a margin call level.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MarginCall {
+    pub target: MarginCallTarget,
+    pub user: Pubkey,
+    // user account or position account
+    pub pubkey: Pubkey,
+    // number of levels the margin rate is under, 1 is the highest level
+    pub level: usize,
+    pub threshold: f64,
+    pub margin_rate: f64,
+    pub timestamp: i64,
+}
+
+// Return how many levels the margin rate is under, the levels are sorted from the highest.
+pub fn margin_call_level(margin_rate: f64, levels: &[f64]) -> usize {
+    levels.iter().filter(|l| margin_rate < **l).count()
+}
+
+// A full position that can be closed by the liquidation plan.
+#[derive(Debug, Clone)]
+pub struct PlanPosition {
+    pub pubkey: Pubkey,
+    pub market_account: Pubkey,
+    pub direction: position::Direction,
+    pub margin: Money,
+    // floating P/L with the funding fee
+    pub profit: Money,
+}
+
+fn is_full_burst(equity: Money, margin_buy_total: Money, margin_sell_total: Money) -> bool {
+    equity.is_below_rate(margin_buy_total.max(margin_sell_total), bcom::BURST_RATE)
+}
+
+fn by_loss(a: &&PlanPosition, b: &&PlanPosition) -> std::cmp::Ordering {
+    a.profit
+        .cmp(&b.profit)
+        .then_with(|| a.pubkey.cmp(&b.pubkey))
+}
+
+// Return the full positions to close, in order, to bring the user back to the burst rate.
+// Closing a position realizes its P/L into the balance, so the equity does not change
+// and only the margin of its direction is released. Only closing a position of the side
+// that sets max(buy margin, sell margin) moves the rate, so each step takes the most losing
+// position of that side, or of either side when both are equal, and the plan stops as soon
+// as equity / max(buy margin, sell margin) >= BURST_RATE or the binding side has no position left.
+// Only the first position of the plan is closed. Once it is confirmed, the user and its
+// positions are fetched again and the rest is planned from them.
+pub fn plan_full_liquidation(
+    equity: Money,
+    margin_buy_total: Money,
+    margin_sell_total: Money,
+    positions: &[PlanPosition],
+) -> Vec<PlanPosition> {
+    let mut buy = margin_buy_total;
+    let mut sell = margin_sell_total;
+    let mut buys: Vec<&PlanPosition> = positions
+        .iter()
+        .filter(|p| matches!(p.direction, position::Direction::Buy))
+        .collect();
+    let mut sells: Vec<&PlanPosition> = positions
+        .iter()
+        .filter(|p| matches!(p.direction, position::Direction::Sell))
+        .collect();
+    buys.sort_by(by_loss);
+    sells.sort_by(by_loss);
+    let mut buys = buys.into_iter().peekable();
+    let mut sells = sells.into_iter().peekable();
+    let mut plan = Vec::new();
+    while is_full_burst(equity, buy, sell) {
+        let next = if buy > sell {
+            buys.next()
+        } else if sell > buy {
+            sells.next()
+        } else {
+            // both sides bind, the rate moves once both are reduced
+            match (buys.peek(), sells.peek()) {
+                (Some(b), Some(s)) => {
+                    if by_loss(b, s) == std::cmp::Ordering::Greater {
+                        sells.next()
+                    } else {
+                        buys.next()
+                    }
+                }
+                _ => None,
+            }
+        };
+        let p = match next {
+            Some(p) => p,
+            None => break,
+        };
+        match p.direction {
+            position::Direction::Buy => buy -= p.margin,
+            position::Direction::Sell => sell -= p.margin,
+        }
+        plan.push(p.clone());
+    }
+    plan
+}
+
+// Floating P/L
+fn evaluate_full_position(u: &ValuedUser, ev: &mut Evaluation) -> UserDynamicData {
+    let mut total_pl = Money::ZERO;
+
+    let mut data = UserDynamicData::default();
+
+    let mut candidates: Vec<PlanPosition> = Vec::with_capacity(u.full.len());
+    let mut burst_positions: Vec<BurstPosition> = Vec::with_capacity(u.full.len());
+    // why the equity of the full positions can not be trusted, a halted or missing price
+    let mut halted: Option<String> = None;
+
+    for p in &u.full {
+        let (fund_rate, pl) = match &p.value {
+            Some(value) => {
+                data.profit += value.pl;
+                burst_positions.push(BurstPosition {
+                    pubkey: p.pubkey,
+                    market_account: p.market_account,
+                    price: value.price,
+                    profit: value.pl,
+                });
+                candidates.push(PlanPosition {
+                    pubkey: p.pubkey,
+                    market_account: p.market_account,
+                    direction: p.direction,
+                    margin: p.margin,
+                    profit: value.pl + value.fund,
+                });
+                if let Some(r) = &value.halted {
+                    halted = Some(r.clone());
+                }
+                (value.fund, value.pl)
+            }
+            None => {
+                ev.warnings.push(format!(
+                    "Cannot get market or price data of full position, continue! position pubkey: {},market_pubkey: {}",
+                    p.pubkey, p.market_account
+                ));
+                halted = Some(format!(
+                    "no market or price data of market {}",
+                    p.market_account
+                ));
+                (Money::ZERO, Money::ZERO)
+            }
+        };
+        ev.positions.push((
+            p.pubkey,
+            PositionDynamicData {
+                profit_rate: pl.rate(p.margin),
+            },
+        ));
+        total_pl += pl + fund_rate;
+    }
+    data.equity = total_pl;
+    let equity = u.balance + total_pl;
+    let margin_buy_total = u.margin_full_buy_total;
+    let margin_sell_total = u.margin_full_sell_total;
+    let margin_rate = equity.rate(margin_buy_total.max(margin_sell_total));
+    if !u.full.is_empty() {
+        ev.full_margin_rate = Some(margin_rate);
+    }
+    // Forced close
+    if is_full_burst(equity, margin_buy_total, margin_sell_total) {
+        // The equity of full positions depends on every market price, do not close on a partial
+        // or untrusted one.
+        if let Some(r) = halted {
+            ev.warnings.push(format!(
+                "full positions can not be valued, skip burst user {}: {}",
+                u.user, r
+            ));
+            return data;
+        }
+        let plan = plan_full_liquidation(equity, margin_buy_total, margin_sell_total, &candidates);
+        if let Some(p) = plan.into_iter().next() {
+            ev.bursts.push(BurstDecision {
+                position: p.pubkey,
+                market_account: p.market_account,
+                full: true,
+                equity_ratio: margin_rate,
+                positions: burst_positions.clone(),
+            });
+        }
+    }
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Deterministic linear congruential generator, so failures can be replayed by seed.
+    struct Lcg(u64);
+
+    impl Lcg {
+        fn next(&mut self) -> u64 {
+            self.0 = self
+                .0
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            self.0 >> 33
+        }
+
+        // units in [lo, hi)
+        fn money(&mut self, lo: i64, hi: i64) -> Money {
+            Money::from_units(lo + (self.next() % (hi - lo) as u64) as i64)
+        }
+    }
+
+    struct Case {
+        equity: Money,
+        buy: Money,
+        sell: Money,
+        positions: Vec<PlanPosition>,
+    }
+
+    fn gen_case(rng: &mut Lcg) -> Case {
+        let n = (rng.next() % 8) as usize + 1;
+        let mut positions = Vec::with_capacity(n);
+        let (mut buy, mut sell) = (Money::ZERO, Money::ZERO);
+        for _ in 0..n {
+            let margin = rng.money(1_000, 1_000_000_000);
+            let direction = if rng.next() % 2 == 0 {
+                buy += margin;
+                position::Direction::Buy
+            } else {
+                sell += margin;
+                position::Direction::Sell
+            };
+            positions.push(PlanPosition {
+                pubkey: Pubkey::new_unique(),
+                market_account: Pubkey::new_unique(),
+                direction,
+                margin,
+                profit: rng.money(-1_000_000_000, 1_000_000_000),
+            });
+        }
+        let equity = rng.money(-500_000_000, 2 * buy.max(sell).units() + 1);
+        Case {
+            equity,
+            buy,
+            sell,
+            positions,
+        }
+    }
+
+    // Return the buy and sell margin left after closing the positions.
+    fn margins_after(c: &Case, plan: &[PlanPosition]) -> (Money, Money) {
+        let (mut buy, mut sell) = (c.buy, c.sell);
+        for p in plan {
+            match p.direction {
+                position::Direction::Buy => buy -= p.margin,
+                position::Direction::Sell => sell -= p.margin,
+            }
+        }
+        (buy, sell)
+    }
+
+    // Return true if the user is still under the burst rate after closing the positions.
+    fn burst_after(c: &Case, plan: &[PlanPosition]) -> bool {
+        let (buy, sell) = margins_after(c, plan);
+        is_full_burst(c.equity, buy, sell)
+    }
+
+    fn is_buy(p: &PlanPosition) -> bool {
+        matches!(p.direction, position::Direction::Buy)
+    }
+
+    // Return true if the side of the position has no position left out of the plan.
+    fn side_exhausted(c: &Case, plan: &[PlanPosition], buy: bool) -> bool {
+        c.positions
+            .iter()
+            .filter(|p| is_buy(p) == buy)
+            .all(|p| plan.iter().any(|v| v.pubkey == p.pubkey))
+    }
+
+    fn plan_position(direction: position::Direction, margin: i64, profit: i64) -> PlanPosition {
+        PlanPosition {
+            pubkey: Pubkey::new_unique(),
+            market_account: Pubkey::new_unique(),
+            direction,
+            margin: Money::from_units(margin),
+            profit: Money::from_units(profit),
+        }
+    }
+
+    #[test]
+    fn plan_stops_as_soon_as_burst_rate_is_restored() {
+        for seed in 0..2000u64 {
+            let mut rng = Lcg(seed);
+            let c = gen_case(&mut rng);
+            let plan = plan_full_liquidation(c.equity, c.buy, c.sell, &c.positions);
+
+            if !is_full_burst(c.equity, c.buy, c.sell) {
+                assert!(plan.is_empty(), "seed {}: healthy user has a plan", seed);
+                continue;
+            }
+            assert!(!plan.is_empty(), "seed {}: empty plan", seed);
+            // Every prefix but the whole plan is still under the burst rate.
+            for i in 0..plan.len() {
+                assert!(
+                    burst_after(&c, &plan[..i]),
+                    "seed {}: plan did not stop after {} positions",
+                    seed,
+                    i
+                );
+            }
+            // The whole plan restores the rate, unless the binding side has nothing left to close.
+            let (buy, sell) = margins_after(&c, &plan);
+            assert!(
+                !burst_after(&c, &plan)
+                    || (buy >= sell && side_exhausted(&c, &plan, true))
+                    || (sell >= buy && side_exhausted(&c, &plan, false)),
+                "seed {}: plan stopped under the burst rate",
+                seed
+            );
+        }
+    }
+
+    #[test]
+    fn plan_closes_only_the_binding_side() {
+        for seed in 0..2000u64 {
+            let mut rng = Lcg(seed);
+            let c = gen_case(&mut rng);
+            let plan = plan_full_liquidation(c.equity, c.buy, c.sell, &c.positions);
+            for (i, p) in plan.iter().enumerate() {
+                let (buy, sell) = margins_after(&c, &plan[..i]);
+                if is_buy(p) {
+                    assert!(buy >= sell, "seed {}: closed a buy under sell margin", seed);
+                } else {
+                    assert!(sell >= buy, "seed {}: closed a sell under buy margin", seed);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn plan_closes_the_most_losing_positions_of_each_side_first() {
+        for seed in 0..2000u64 {
+            let mut rng = Lcg(seed);
+            let c = gen_case(&mut rng);
+            let plan = plan_full_liquidation(c.equity, c.buy, c.sell, &c.positions);
+            for side in [true, false] {
+                let closed: Vec<&PlanPosition> =
+                    plan.iter().filter(|p| is_buy(p) == side).collect();
+                for w in closed.windows(2) {
+                    assert!(w[0].profit <= w[1].profit, "seed {}: plan not sorted", seed);
+                }
+                // No position of the side left out of the plan loses more than the last one closed.
+                if let Some(last) = closed.last() {
+                    for p in c.positions.iter().filter(|p| is_buy(p) == side) {
+                        if !plan.iter().any(|v| v.pubkey == p.pubkey) {
+                            assert!(p.profit >= last.profit, "seed {}: skipped a loser", seed);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn plan_skips_the_most_losing_position_of_the_non_binding_side() {
+        let buy_small = plan_position(position::Direction::Buy, 300_000_000, -100_000_000);
+        let buy_large = plan_position(position::Direction::Buy, 500_000_000, -50_000_000);
+        let sell = plan_position(position::Direction::Sell, 400_000_000, -900_000_000);
+        let positions = vec![sell.clone(), buy_large.clone(), buy_small.clone()];
+        let buy = buy_small.margin + buy_large.margin;
+        // under the burst rate against the buy margin only
+        let equity = Money::from_units((buy.units() as f64 * bcom::BURST_RATE) as i64 - 1);
+        assert!(equity.is_below_rate(buy, bcom::BURST_RATE));
+        assert!(!equity.is_below_rate(sell.margin, bcom::BURST_RATE));
+
+        let plan = plan_full_liquidation(equity, buy, sell.margin, &positions);
+        assert_eq!(plan.len(), 1);
+        assert_eq!(plan[0].pubkey, buy_small.pubkey);
+    }
+
+    const MARGIN: i64 = 1_000_000_000;
+
+    // Return the equity at which a margin is exactly at the burst rate.
+    fn burst_equity(margin: i64) -> i64 {
+        Money::from_units(margin).mul_rate(bcom::BURST_RATE).units()
+    }
+
+    fn valued(direction: position::Direction, margin: i64, pl: i64) -> ValuedPosition {
+        ValuedPosition {
+            pubkey: Pubkey::new_unique(),
+            market_account: Pubkey::new_unique(),
+            direction,
+            margin: Money::from_units(margin),
+            value: Some(Valuation {
+                pl: Money::from_units(pl),
+                fund: Money::ZERO,
+                price: Money::from_units(1_000_000),
+                pair: "BTC/USD".to_string(),
+                halted: None,
+            }),
+        }
+    }
+
+    fn halted(mut p: ValuedPosition) -> ValuedPosition {
+        if let Some(v) = &mut p.value {
+            v.halted = Some("price is stale".to_string());
+        }
+        p
+    }
+
+    fn missing(mut p: ValuedPosition) -> ValuedPosition {
+        p.value = None;
+        p
+    }
+
+    fn valued_user(
+        balance: i64,
+        independent: Vec<ValuedPosition>,
+        full: Vec<ValuedPosition>,
+    ) -> ValuedUser {
+        let side = |buy: bool| -> Money {
+            full.iter()
+                .filter(|p| matches!(p.direction, position::Direction::Buy) == buy)
+                .map(|p| p.margin)
+                .sum()
+        };
+        let margin_full_buy_total = side(true);
+        let margin_full_sell_total = side(false);
+        let margin_total = independent.iter().map(|p| p.margin).sum::<Money>()
+            + margin_full_buy_total
+            + margin_full_sell_total;
+        ValuedUser {
+            user: Pubkey::new_unique(),
+            balance: Money::from_units(balance),
+            margin_total,
+            margin_full_buy_total,
+            margin_full_sell_total,
+            independent,
+            full,
+        }
+    }
+
+    struct EvalCase {
+        name: &'static str,
+        user: ValuedUser,
+        // indexes into independent then full positions
+        bursts: Vec<usize>,
+        warnings: usize,
+        full_margin_rate: bool,
+    }
+
+    fn eval_cases() -> Vec<EvalCase> {
+        let buy = position::Direction::Buy;
+        let sell = position::Direction::Sell;
+        let under = burst_equity(MARGIN) - MARGIN - 1;
+        let at = burst_equity(MARGIN) - MARGIN;
+        vec![
+            EvalCase {
+                name: "independent only",
+                user: valued_user(
+                    0,
+                    vec![valued(buy, MARGIN, 0), valued(sell, MARGIN, under)],
+                    vec![],
+                ),
+                bursts: vec![1],
+                warnings: 0,
+                full_margin_rate: false,
+            },
+            EvalCase {
+                name: "full only",
+                user: valued_user(
+                    burst_equity(MARGIN) - 1,
+                    vec![],
+                    vec![valued(buy, MARGIN, 0), valued(sell, MARGIN / 2, 0)],
+                ),
+                bursts: vec![0],
+                warnings: 0,
+                full_margin_rate: true,
+            },
+            EvalCase {
+                name: "full closes the first step of the plan only",
+                user: valued_user(
+                    burst_equity(MARGIN) - 1,
+                    vec![],
+                    vec![valued(buy, MARGIN, -1), valued(buy, MARGIN, 0)],
+                ),
+                bursts: vec![0],
+                warnings: 0,
+                full_margin_rate: true,
+            },
+            EvalCase {
+                name: "halted independent market",
+                user: valued_user(0, vec![halted(valued(buy, MARGIN, under))], vec![]),
+                bursts: vec![],
+                warnings: 1,
+                full_margin_rate: false,
+            },
+            EvalCase {
+                name: "halted full market",
+                user: valued_user(
+                    burst_equity(MARGIN) - 1,
+                    vec![],
+                    vec![valued(buy, MARGIN, 0), halted(valued(sell, MARGIN / 2, 0))],
+                ),
+                bursts: vec![],
+                warnings: 1,
+                full_margin_rate: true,
+            },
+            EvalCase {
+                name: "missing independent quote",
+                user: valued_user(0, vec![missing(valued(buy, MARGIN, under))], vec![]),
+                bursts: vec![],
+                warnings: 1,
+                full_margin_rate: false,
+            },
+            EvalCase {
+                name: "missing full quote",
+                user: valued_user(
+                    burst_equity(MARGIN) - 1,
+                    vec![],
+                    vec![valued(buy, MARGIN, 0), missing(valued(sell, MARGIN / 2, 0))],
+                ),
+                bursts: vec![],
+                warnings: 2,
+                full_margin_rate: true,
+            },
+            EvalCase {
+                name: "independent at the burst rate",
+                user: valued_user(0, vec![valued(buy, MARGIN, at)], vec![]),
+                bursts: vec![],
+                warnings: 0,
+                full_margin_rate: false,
+            },
+            EvalCase {
+                name: "independent one unit under the burst rate",
+                user: valued_user(0, vec![valued(buy, MARGIN, at - 1)], vec![]),
+                bursts: vec![0],
+                warnings: 0,
+                full_margin_rate: false,
+            },
+            EvalCase {
+                name: "full at the burst rate",
+                user: valued_user(burst_equity(MARGIN), vec![], vec![valued(buy, MARGIN, 0)]),
+                bursts: vec![],
+                warnings: 0,
+                full_margin_rate: true,
+            },
+            EvalCase {
+                name: "full one unit under the burst rate",
+                user: valued_user(
+                    burst_equity(MARGIN) - 1,
+                    vec![],
+                    vec![valued(buy, MARGIN, 0)],
+                ),
+                bursts: vec![0],
+                warnings: 0,
+                full_margin_rate: true,
+            },
+        ]
+    }
+
+    #[test]
+    fn evaluate_valued_cases() {
+        for c in eval_cases() {
+            let ev = evaluate_valued(&c.user);
+            let positions: Vec<Pubkey> = c
+                .user
+                .independent
+                .iter()
+                .chain(c.user.full.iter())
+                .map(|p| p.pubkey)
+                .collect();
+            let expected: Vec<Pubkey> = c.bursts.iter().map(|i| positions[*i]).collect();
+            let bursts: Vec<Pubkey> = ev.bursts.iter().map(|b| b.position).collect();
+            assert_eq!(bursts, expected, "{}: bursts", c.name);
+            assert_eq!(
+                ev.warnings.len(),
+                c.warnings,
+                "{}: {:?}",
+                c.name,
+                ev.warnings
+            );
+            assert_eq!(
+                ev.full_margin_rate.is_some(),
+                c.full_margin_rate,
+                "{}: full margin rate",
+                c.name
+            );
+            if !c.bursts.is_empty() {
+                let rate = ev.min_margin_rate().unwrap();
+                assert!(rate < bcom::BURST_RATE, "{}: min margin rate", c.name);
+            }
+        }
+    }
+
+    #[test]
+    fn min_margin_rate_of_every_position() {
+        let mut ev = Evaluation::default();
+        assert_eq!(ev.min_margin_rate(), None);
+        ev.full_margin_rate = Some(0.3);
+        assert_eq!(ev.min_margin_rate(), Some(0.3));
+        ev.margin_rates = vec![(Pubkey::new_unique(), 0.5), (Pubkey::new_unique(), 0.2)];
+        assert_eq!(ev.min_margin_rate(), Some(0.2));
+        ev.full_margin_rate = None;
+        assert_eq!(ev.min_margin_rate(), Some(0.2));
+    }
+
+    #[test]
+    fn margin_call_level_counts_the_levels_crossed() {
+        let levels = [bcom::BURST_RATE * 1.5, bcom::BURST_RATE * 1.2];
+        assert_eq!(margin_call_level(bcom::BURST_RATE * 2.0, &levels), 0);
+        assert_eq!(margin_call_level(bcom::BURST_RATE * 1.3, &levels), 1);
+        assert_eq!(margin_call_level(bcom::BURST_RATE * 1.1, &levels), 2);
+        assert_eq!(margin_call_level(bcom::BURST_RATE * 2.0, &[]), 0);
+    }
+}
@@ -1,14 +1,36 @@
+use super::machine;
 use crate::{com, config};
 use anchor_client::solana_sdk::account::Account;
-use sled::{Batch, Db};
+use bond::state::position;
+use log::info;
+use serde::{de::DeserializeOwned, Serialize};
+use sled::transaction::TransactionResult;
+use sled::{Batch, Db, Transactional, Tree};
 use solana_sdk::pubkey::Pubkey;
+use std::collections::HashSet;
+use std::convert::{TryFrom, TryInto};
 use std::fmt;
+use std::ops::Range;
+use std::path::Path;
 use std::str::FromStr;
 
+// The format version of the db.
+// Version 0 is the legacy json encoding of the values, version 1 encodes them with bincode,
+// version 2 adds the history indexes.
+pub const FORMAT_VERSION: u8 = 2;
+// The encoding of the values, stored in the first byte of every value.
+const ENCODING_VERSION: u8 = 1;
+const META_TREE: &str = "meta";
+const FORMAT_VERSION_KEY: &str = "format_version";
+// values rewritten in one batch by the migration
+const MIGRATE_BATCH: usize = 1000;
+
 #[derive(Debug, Clone)]
 pub enum Prefix {
     Active = 1,
     History,
+    Funding,
+    DryRun,
     None,
 }
 #[derive(Clone)]
@@ -64,6 +86,8 @@ impl fmt::Display for Prefix {
         let t = match *self {
             Self::Active => "active",
             Self::History => "history",
+            Self::Funding => "funding",
+            Self::DryRun => "dryrun",
             _ => "",
         };
         write!(f, "{}", t)
@@ -76,6 +100,8 @@ impl FromStr for Prefix {
         let r = match s {
             "active" => Prefix::Active,
             "history" => Prefix::History,
+            "funding" => Prefix::Funding,
+            "dryrun" => Prefix::DryRun,
             _ => Prefix::None,
         };
         Ok(r)
@@ -89,15 +115,361 @@ impl FromStr for Keys {
         Ok(Keys { keys })
     }
 }
+
+// Account writes applied together, e.g. the updates received in one round of keep_account.
+#[derive(Default)]
+pub struct WriteBatch {
+    accounts: Batch,
+    slots: Batch,
+    // closed positions to add to the history indexes
+    history: Vec<HistoryIndex>,
+    // the accounts whose slot the batch saves or removes
+    slot_accounts: Vec<Pubkey>,
+    len: usize,
+}
+
+impl WriteBatch {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn save_to_active(&mut self, ks: &Keys, account: &Account) -> anyhow::Result<()> {
+        let value = encode(account)?;
+        self.accounts.insert(ks.get_storage_key().as_bytes(), value);
+        self.len += 1;
+        Ok(())
+    }
+
+    // Move the active account to the history, a position is indexed as closed at close_time.
+    pub fn save_as_history(
+        &mut self,
+        ks: &mut Keys,
+        account: &Account,
+        close_time: i64,
+    ) -> anyhow::Result<()> {
+        let value = encode(account)?;
+        self.accounts.remove(ks.get_storage_key().as_bytes());
+        ks.set_prefix(Prefix::History);
+        let history_key = ks.get_storage_key();
+        if let Some(index) = HistoryIndex::new(ks, &history_key, account, close_time) {
+            if !self
+                .history
+                .iter()
+                .any(|v| v.history_key == index.history_key)
+            {
+                self.history.push(index);
+            }
+        }
+        self.accounts.insert(history_key.as_bytes(), value);
+        self.len += 1;
+        Ok(())
+    }
+
+    pub fn save_slot(&mut self, pubkey: &Pubkey, slot: u64) {
+        self.slots
+            .insert(&pubkey.to_bytes()[..], slot.to_be_bytes().to_vec());
+        self.slot_accounts.push(*pubkey);
+        self.len += 1;
+    }
+
+    // Forget the slot of an account that left the active set.
+    pub fn remove_slot(&mut self, pubkey: &Pubkey) {
+        self.slots.remove(&pubkey.to_bytes()[..]);
+        self.slot_accounts.push(*pubkey);
+        self.len += 1;
+    }
+
+    pub fn slot_accounts(&self) -> &[Pubkey] {
+        &self.slot_accounts
+    }
+
+    // Return true if the batch moves the position of the keys to the history.
+    pub fn is_history(&self, ks: &Keys) -> bool {
+        let key = history_key(ks);
+        self.history.iter().any(|v| v.history_key == key.as_bytes())
+    }
+}
+
+fn history_key(ks: &Keys) -> String {
+    format!("{}{}", Prefix::History.prefix(), ks.keys[1..].join("_"))
+}
+
+// The index entries of a closed position. The close time is the unix time of the slot of the
+// update that closed it, the index keys end with the big endian close time and the position.
+struct HistoryIndex {
+    history_key: Vec<u8>,
+    position: Pubkey,
+    market: Pubkey,
+    status: u8,
+    close_time: i64,
+}
+
+impl HistoryIndex {
+    fn new(ks: &Keys, history_key: &str, account: &Account, close_time: i64) -> Option<Self> {
+        let s: machine::State = account.into();
+        match s {
+            machine::State::Position(p) => Some(Self {
+                history_key: history_key.as_bytes().to_vec(),
+                position: Pubkey::from_str(ks.get_end().as_str()).ok()?,
+                market: p.market_account,
+                status: status_tag(&p.position_status),
+                close_time,
+            }),
+            _ => None,
+        }
+    }
+
+    fn time_key(&self) -> Vec<u8> {
+        let mut k = time_bytes(self.close_time).to_vec();
+        k.extend_from_slice(&self.position.to_bytes());
+        k
+    }
+
+    fn market_key(&self) -> Vec<u8> {
+        let mut k = self.market.to_bytes().to_vec();
+        k.extend(self.time_key());
+        k
+    }
+
+    // the status is kept in the value to filter the positions of a market by it
+    fn market_value(&self) -> Vec<u8> {
+        let mut v = vec![self.status];
+        v.extend_from_slice(&self.history_key);
+        v
+    }
+
+    fn status_key(&self) -> Vec<u8> {
+        let mut k = vec![self.status];
+        k.extend(self.time_key());
+        k
+    }
+}
+
 #[derive(Clone)]
 pub struct Storage {
     db: Db,
+    // key is account pubkey, value is the big endian context slot of the saved account
+    slots: Tree,
+    // key is FORMAT_VERSION_KEY, value is the format version of the values
+    meta: Tree,
+    // indexes of the closed positions, built when a position is first saved as history.
+    // key is market + close time + position, value is status + history key
+    history_by_market: Tree,
+    // key is close time + position, value is history key
+    history_by_time: Tree,
+    // key is status + close time + position, value is history key
+    history_by_status: Tree,
+    // flush after every write instead of in the background
+    flush_every_write: bool,
 }
 impl Storage {
+    // Open the local db, it must be in the current format version.
     pub fn new(config: config::Config) -> anyhow::Result<Self> {
-        let path = config.store_path.join("accounts");
-        let db = sled::open(path).map_err(|e| com::CliError::DBError(e.to_string()))?;
-        Ok(Self { db })
+        Self::open(config)?.checked()
+    }
+
+    fn checked(self) -> anyhow::Result<Self> {
+        let version = self.format_version()?;
+        if version < FORMAT_VERSION {
+            return Err(com::CliError::DBError(format!(
+                "the local db is in format version {}, run `scale db migrate` to upgrade it to version {}",
+                version, FORMAT_VERSION
+            ))
+            .into());
+        }
+        if version > FORMAT_VERSION {
+            return Err(com::CliError::DBError(format!(
+                "the local db is in format version {}, newer than the supported version {}",
+                version, FORMAT_VERSION
+            ))
+            .into());
+        }
+        Ok(self)
+    }
+
+    fn open(config: config::Config) -> anyhow::Result<Self> {
+        Self::open_at(&config.store_path.join("accounts"), &config.storage)
+    }
+
+    fn open_at(path: &Path, config: &config::StorageConfig) -> anyhow::Result<Self> {
+        let flush_every_write = config.flush == config::FlushPolicy::Every;
+        let flush_every_ms = if flush_every_write {
+            None
+        } else {
+            Some(config.flush_interval_ms.max(1))
+        };
+        let db = sled::Config::new()
+            .path(path)
+            .flush_every_ms(flush_every_ms)
+            .open()
+            .map_err(|e| com::CliError::DBError(e.to_string()))?;
+        let slots = db
+            .open_tree("slots")
+            .map_err(|e| com::CliError::DBError(e.to_string()))?;
+        let meta = db
+            .open_tree(META_TREE)
+            .map_err(|e| com::CliError::DBError(e.to_string()))?;
+        let history_by_market = db
+            .open_tree("history_by_market")
+            .map_err(|e| com::CliError::DBError(e.to_string()))?;
+        let history_by_time = db
+            .open_tree("history_by_time")
+            .map_err(|e| com::CliError::DBError(e.to_string()))?;
+        let history_by_status = db
+            .open_tree("history_by_status")
+            .map_err(|e| com::CliError::DBError(e.to_string()))?;
+        Ok(Self {
+            db,
+            slots,
+            meta,
+            history_by_market,
+            history_by_time,
+            history_by_status,
+            flush_every_write,
+        })
+    }
+
+    // The format version of the values, an empty db is created in the current version.
+    pub fn format_version(&self) -> anyhow::Result<u8> {
+        match self.meta.get(FORMAT_VERSION_KEY)? {
+            Some(v) => v
+                .first()
+                .copied()
+                .ok_or_else(|| com::CliError::DBError("invalid format version".to_string()).into()),
+            None => {
+                if self.db.is_empty() {
+                    self.meta.insert(FORMAT_VERSION_KEY, vec![FORMAT_VERSION])?;
+                    Ok(FORMAT_VERSION)
+                } else {
+                    Ok(0)
+                }
+            }
+        }
+    }
+
+    // Migrate the db to the current format version, return the number of values rewritten
+    // or indexed. An interrupted migration can be run again, it skips the migrated values.
+    pub fn migrate(&self) -> anyhow::Result<usize> {
+        let version = self.format_version()?;
+        if version >= FORMAT_VERSION {
+            return Ok(0);
+        }
+        let mut n = 0;
+        if version < 1 {
+            n += self.migrate_values()?;
+        }
+        if version < 2 {
+            n += self.index_history()?;
+        }
+        self.meta.insert(FORMAT_VERSION_KEY, vec![FORMAT_VERSION])?;
+        self.db
+            .flush()
+            .map_err(|e| com::CliError::DBError(e.to_string()))?;
+        Ok(n)
+    }
+
+    // Rewrite the legacy json values in the current encoding.
+    fn migrate_values(&self) -> anyhow::Result<usize> {
+        let mut n = 0;
+        let mut batch = Batch::default();
+        let mut len = 0;
+        for i in self.db.iter() {
+            let (k, v) = i?;
+            if v.first() == Some(&ENCODING_VERSION) {
+                continue;
+            }
+            let key =
+                String::from_utf8(k.to_vec()).map_err(|e| com::CliError::DBError(e.to_string()))?;
+            let keys = Keys::from_str(key.as_str())?;
+            let value = migrate_json(&keys.get_prefix(), &v).map_err(|e| {
+                com::CliError::DBError(format!("migrate value of {} error:{}", key, e))
+            })?;
+            batch.insert(k, value);
+            len += 1;
+            if len == MIGRATE_BATCH {
+                self.db.apply_batch(batch)?;
+                batch = Batch::default();
+                n += len;
+                len = 0;
+                info!("migrated {} values", n);
+            }
+        }
+        self.db.apply_batch(batch)?;
+        n += len;
+        Ok(n)
+    }
+
+    // Add the history positions missing from the history indexes. The close time of the
+    // positions saved before the indexes is not known, they are indexed at time 0.
+    fn index_history(&self) -> anyhow::Result<usize> {
+        // the time index is written last, a position in it is in all the indexes
+        let mut indexed = HashSet::new();
+        for i in self.history_by_time.iter() {
+            let (_, v) = i?;
+            indexed.insert(v.to_vec());
+        }
+        let mut n = 0;
+        let mut by_market = Batch::default();
+        let mut by_time = Batch::default();
+        let mut by_status = Batch::default();
+        let mut len = 0;
+        for i in self.scan_prefix(&Prefix::History) {
+            let (k, v) = i?;
+            if indexed.contains(k.as_ref()) {
+                continue;
+            }
+            let key =
+                String::from_utf8(k.to_vec()).map_err(|e| com::CliError::DBError(e.to_string()))?;
+            let keys = Keys::from_str(key.as_str())?;
+            let account: Account = decode(&v).map_err(|e| {
+                com::CliError::DBError(format!("index history of {} error:{}", key, e))
+            })?;
+            let index = match HistoryIndex::new(&keys, &key, &account, 0) {
+                Some(index) => index,
+                None => continue,
+            };
+            by_market.insert(index.market_key(), index.market_value());
+            by_status.insert(index.status_key(), index.history_key.clone());
+            by_time.insert(index.time_key(), index.history_key.clone());
+            len += 1;
+            if len == MIGRATE_BATCH {
+                self.apply_index(by_market, by_status, by_time)?;
+                by_market = Batch::default();
+                by_status = Batch::default();
+                by_time = Batch::default();
+                n += len;
+                len = 0;
+                info!("indexed {} history positions", n);
+            }
+        }
+        self.apply_index(by_market, by_status, by_time)?;
+        n += len;
+        Ok(n)
+    }
+
+    fn apply_index(
+        &self,
+        by_market: Batch,
+        by_status: Batch,
+        by_time: Batch,
+    ) -> anyhow::Result<()> {
+        self.history_by_market.apply_batch(by_market)?;
+        self.history_by_status.apply_batch(by_status)?;
+        self.history_by_time.apply_batch(by_time)?;
+        Ok(())
+    }
+
+    fn written(&self) -> anyhow::Result<()> {
+        if self.flush_every_write {
+            self.db
+                .flush()
+                .map_err(|e| com::CliError::DBError(e.to_string()))?;
+        }
+        Ok(())
     }
 
     // Active load Active account
@@ -107,10 +479,10 @@ impl Storage {
     }
 
     fn save_one(&self, ks: &Keys, account: &Account) -> anyhow::Result<()> {
-        let value = serde_json::to_vec(account)?;
+        let value = encode(account)?;
         let key = ks.get_storage_key();
         self.db.insert(key.as_bytes(), value)?;
-        Ok(())
+        self.written()
     }
 
     pub fn save_to_active(&self, ks: &Keys, account: &Account) -> anyhow::Result<()> {
@@ -122,31 +494,95 @@ impl Storage {
         self.save_one(ks, account)
     }
 
-    pub fn save_as_history(&self, ks: &mut Keys, account: &Account) -> anyhow::Result<()> {
-        let value = serde_json::to_vec(account)?;
-        let value = value.as_slice();
+    // Return true if the account of the keys was moved to the history.
+    pub fn is_history(&self, ks: &Keys) -> anyhow::Result<bool> {
+        Ok(self.db.contains_key(history_key(ks).as_bytes())?)
+    }
+
+    pub fn save_as_history(
+        &self,
+        ks: &mut Keys,
+        account: &Account,
+        close_time: i64,
+    ) -> anyhow::Result<()> {
+        let mut batch = WriteBatch::default();
+        batch.save_as_history(ks, account, close_time)?;
+        self.apply(&batch)
+    }
+
+    pub fn save_record<T: Serialize>(&self, ks: &Keys, record: &T) -> anyhow::Result<()> {
+        let value = encode(record)?;
         let key = ks.get_storage_key();
-        ks.set_prefix(Prefix::History);
-        let history_key = ks.get_storage_key();
-        self.db
-            .transaction::<_, (), anyhow::Error>(|tx| {
-                tx.remove(key.as_bytes())?;
-                tx.insert(history_key.as_bytes(), value)?;
-                Ok(())
-            })
-            .map_err(|e| com::CliError::DBError(e.to_string()))?;
-        Ok(())
+        self.db.insert(key.as_bytes(), value)?;
+        self.written()
     }
 
+    // Save the accounts as active in one atomic write.
     pub fn save_batch(&self, kv: Vec<(&Keys, &Account)>) -> anyhow::Result<()> {
-        let mut batch = Batch::default();
+        let mut batch = WriteBatch::default();
         for v in kv {
-            let value = serde_json::to_vec(v.1)?;
-            let key = v.0.get_storage_key();
-            batch.insert(key.as_bytes(), value);
+            batch.save_to_active(v.0, v.1)?;
+        }
+        self.apply(&batch)
+    }
+
+    // Apply the account writes and their slots atomically.
+    pub fn apply(&self, batch: &WriteBatch) -> anyhow::Result<()> {
+        let rs: TransactionResult<(), ()> = (
+            &*self.db,
+            &self.slots,
+            &self.history_by_market,
+            &self.history_by_time,
+            &self.history_by_status,
+        )
+            .transaction(|(db, slots, by_market, by_time, by_status)| {
+                for index in &batch.history {
+                    // already indexed when it was first saved as history
+                    if db.get(&index.history_key)?.is_some() {
+                        continue;
+                    }
+                    by_market.insert(index.market_key(), index.market_value())?;
+                    by_time.insert(index.time_key(), index.history_key.clone())?;
+                    by_status.insert(index.status_key(), index.history_key.clone())?;
+                }
+                db.apply_batch(&batch.accounts)?;
+                slots.apply_batch(&batch.slots)?;
+                Ok(())
+            });
+        rs.map_err(|e| com::CliError::DBError(format!("{:?}", e)))?;
+        self.written()
+    }
+
+    // Write the dirty buffers to disk, return the number of bytes flushed.
+    pub async fn flush(&self) -> anyhow::Result<usize> {
+        let n = self
+            .db
+            .flush_async()
+            .await
+            .map_err(|e| com::CliError::DBError(e.to_string()))?;
+        Ok(n)
+    }
+
+    pub fn get_slot(&self, pubkey: &Pubkey) -> anyhow::Result<Option<u64>> {
+        match self.slots.get(pubkey.to_bytes())? {
+            Some(v) => Ok(Some(decode_slot(&v)?)),
+            None => Ok(None),
         }
-        Ok(())
     }
+
+    // All the saved slots, keyed by account.
+    pub fn get_slots(&self) -> anyhow::Result<Vec<(Pubkey, u64)>> {
+        let mut rs = Vec::new();
+        for i in self.slots.iter() {
+            let (k, v) = i?;
+            let pubkey = <[u8; 32]>::try_from(k.as_ref())
+                .map(Pubkey::new_from_array)
+                .map_err(|e| com::CliError::DBError(e.to_string()))?;
+            rs.push((pubkey, decode_slot(&v)?));
+        }
+        Ok(rs)
+    }
+
     pub fn get_position_history_list(&self, pubkey: &Pubkey) -> sled::Iter {
         let keys = Keys::new(Prefix::History)
             .add("position".to_string())
@@ -154,4 +590,650 @@ impl Storage {
         let key = keys.get_storage_key();
         self.db.scan_prefix(key.as_bytes())
     }
+
+    pub fn get_funding_record_list(&self, pubkey: &Pubkey) -> sled::Iter {
+        let keys = Keys::new(Prefix::Funding).add(pubkey.to_string());
+        let key = keys.get_storage_key();
+        self.db.scan_prefix(key.as_bytes())
+    }
+
+    // The positions of a market closed in [from, to), optionally only the ones of a status.
+    // Times are unix timestamps in seconds, the result is ordered by close time.
+    pub fn get_history_by_market(
+        &self,
+        market: &Pubkey,
+        status: Option<&position::PositionStatus>,
+        from: i64,
+        to: i64,
+    ) -> anyhow::Result<Vec<(Pubkey, Account)>> {
+        let mut rs = Vec::new();
+        if from >= to {
+            return Ok(rs);
+        }
+        let status = status.map(status_tag);
+        for i in self
+            .history_by_market
+            .range(time_range(&market.to_bytes(), from, to))
+        {
+            let (k, v) = i?;
+            match v.split_first() {
+                Some((s, history_key)) if status.map_or(true, |t| t == *s) => {
+                    self.push_history(&mut rs, &k, history_key)?;
+                }
+                _ => {}
+            }
+        }
+        Ok(rs)
+    }
+
+    // The positions closed in [from, to).
+    pub fn get_history_by_time(
+        &self,
+        from: i64,
+        to: i64,
+    ) -> anyhow::Result<Vec<(Pubkey, Account)>> {
+        self.scan_history(&self.history_by_time, &[], from, to)
+    }
+
+    // The positions of a status closed in [from, to).
+    pub fn get_history_by_status(
+        &self,
+        status: &position::PositionStatus,
+        from: i64,
+        to: i64,
+    ) -> anyhow::Result<Vec<(Pubkey, Account)>> {
+        self.scan_history(&self.history_by_status, &[status_tag(status)], from, to)
+    }
+
+    fn scan_history(
+        &self,
+        tree: &Tree,
+        prefix: &[u8],
+        from: i64,
+        to: i64,
+    ) -> anyhow::Result<Vec<(Pubkey, Account)>> {
+        let mut rs = Vec::new();
+        if from >= to {
+            return Ok(rs);
+        }
+        for i in tree.range(time_range(prefix, from, to)) {
+            let (k, v) = i?;
+            self.push_history(&mut rs, &k, &v)?;
+        }
+        Ok(rs)
+    }
+
+    fn push_history(
+        &self,
+        rs: &mut Vec<(Pubkey, Account)>,
+        index_key: &[u8],
+        history_key: &[u8],
+    ) -> anyhow::Result<()> {
+        // the index keys end with the position
+        let start = index_key.len().checked_sub(32).ok_or_else(|| {
+            com::CliError::DBError(format!("invalid history index key: {:?}", index_key))
+        })?;
+        let position = <[u8; 32]>::try_from(&index_key[start..])
+            .map(Pubkey::new_from_array)
+            .map_err(|e| com::CliError::DBError(e.to_string()))?;
+        if let Some(v) = self.db.get(history_key)? {
+            rs.push((position, decode(&v)?));
+        }
+        Ok(())
+    }
+}
+
+fn status_tag(status: &position::PositionStatus) -> u8 {
+    match status {
+        position::PositionStatus::NormalClosing => 1,
+        position::PositionStatus::ForceClosing => 2,
+        _ => 0,
+    }
+}
+
+fn time_bytes(t: i64) -> [u8; 8] {
+    (t.max(0) as u64).to_be_bytes()
+}
+
+fn time_range(prefix: &[u8], from: i64, to: i64) -> Range<Vec<u8>> {
+    let mut start = prefix.to_vec();
+    start.extend_from_slice(&time_bytes(from));
+    let mut end = prefix.to_vec();
+    end.extend_from_slice(&time_bytes(to));
+    start..end
+}
+
+fn decode_slot(v: &[u8]) -> anyhow::Result<u64> {
+    let bytes: [u8; 8] = v
+        .try_into()
+        .map_err(|_| com::CliError::DBError(format!("invalid slot value: {:?}", v)))?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+// Migrate the local db to the current format version.
+pub fn migrate(config: config::Config) -> anyhow::Result<()> {
+    let storage = Storage::open(config)?;
+    let version = storage.format_version()?;
+    let n = storage.migrate()?;
+    println!(
+        "migrate local db success!\nfrom version: {}\nto version: {}\nvalues: {}",
+        version, FORMAT_VERSION, n
+    );
+    Ok(())
+}
+
+// Encode a value in the current encoding version.
+pub fn encode<T: Serialize>(v: &T) -> anyhow::Result<Vec<u8>> {
+    let mut buf = vec![ENCODING_VERSION];
+    bincode::serialize_into(&mut buf, v).map_err(|e| com::CliError::DBError(e.to_string()))?;
+    Ok(buf)
+}
+
+pub fn decode<T: DeserializeOwned>(v: &[u8]) -> anyhow::Result<T> {
+    match v.split_first() {
+        Some((&ENCODING_VERSION, body)) => bincode::deserialize(body)
+            .map_err(|e| com::CliError::DeserializeError(e.to_string()).into()),
+        Some((version, _)) => Err(com::CliError::DeserializeError(format!(
+            "unsupported encoding version: {}",
+            version
+        ))
+        .into()),
+        None => Err(com::CliError::DeserializeError("empty value".to_string()).into()),
+    }
+}
+
+// Encode a legacy json value by the type stored under its prefix.
+fn migrate_json(p: &Prefix, v: &[u8]) -> anyhow::Result<Vec<u8>> {
+    match p {
+        Prefix::Active | Prefix::History => encode(&from_json::<Account>(v)?),
+        Prefix::Funding => encode(&from_json::<machine::FundingRecord>(v)?),
+        Prefix::DryRun => encode(&from_json::<machine::DryRunRecord>(v)?),
+        Prefix::None => Err(com::CliError::DBError("unknown key prefix".to_string()).into()),
+    }
+}
+
+fn from_json<T: DeserializeOwned>(v: &[u8]) -> anyhow::Result<T> {
+    Ok(serde_json::from_slice(v).map_err(|e| com::CliError::JsonError(e.to_string()))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::risk;
+    use super::*;
+    use anchor_client::anchor_lang::{AccountDeserialize, AccountSerialize, Discriminator};
+    use std::path::PathBuf;
+
+    // A directory for one test db, removed when the test ends.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "scale-robot-storage-{}-{}",
+                name,
+                std::process::id()
+            ));
+            let _ = std::fs::remove_dir_all(&path);
+            Self(path)
+        }
+
+        fn open(&self) -> Storage {
+            Storage::open_at(&self.0, &config::StorageConfig::default()).unwrap()
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn account(lamports: u64, data: Vec<u8>) -> Account {
+        Account {
+            lamports,
+            data,
+            owner: com::id(),
+            executable: false,
+            rent_epoch: 0,
+        }
+    }
+
+    // A position account of the market, open if there is no status, the other fields are zero.
+    fn position_account(market: Pubkey, status: Option<position::PositionStatus>) -> Account {
+        let mut data = vec![0u8; 8 + position::Position::LEN];
+        data[..8].copy_from_slice(&position::Position::discriminator());
+        let mut p = position::Position::try_deserialize(&mut &data[..]).unwrap();
+        p.market_account = market;
+        if let Some(status) = status {
+            p.position_status = status;
+        }
+        let mut buf = Vec::new();
+        p.try_serialize(&mut buf).unwrap();
+        data[..buf.len()].copy_from_slice(&buf);
+        account(1, data)
+    }
+
+    fn position_keys(user: &Pubkey, position: &Pubkey) -> Keys {
+        Keys::new(Prefix::Active)
+            .add("position".to_string())
+            .add(user.to_string())
+            .add(position.to_string())
+    }
+
+    fn get<T: DeserializeOwned>(storage: &Storage, key: &str) -> Option<T> {
+        storage
+            .db
+            .get(key.as_bytes())
+            .unwrap()
+            .map(|v| decode(&v).unwrap())
+    }
+
+    fn funding_record(user: Pubkey) -> machine::FundingRecord {
+        machine::FundingRecord {
+            user,
+            period: 1_700_000_000,
+            full_fund: com::Money::from_units(-1_500_000),
+            independent_fund: com::Money::from_units(250),
+            positions: vec![machine::FundingPosition {
+                pubkey: Pubkey::new_unique(),
+                market_account: Pubkey::new_unique(),
+                full: true,
+                fund: com::Money::from_units(-1_500_000),
+            }],
+        }
+    }
+
+    fn dry_run_record(user: Pubkey) -> machine::DryRunRecord {
+        machine::DryRunRecord {
+            user,
+            position: Pubkey::new_unique(),
+            market_account: Pubkey::new_unique(),
+            timestamp: 1_700_000_123,
+            equity_ratio: 0.42,
+            positions: vec![risk::BurstPosition {
+                pubkey: Pubkey::new_unique(),
+                market_account: Pubkey::new_unique(),
+                price: com::Money::from_units(2_000_000_000),
+                profit: com::Money::from_units(-3_000_001),
+            }],
+        }
+    }
+
+    // The keys and json values of a db written before the format version, the amounts of
+    // the records are f64 base units as they were then.
+    fn legacy_values(user: Pubkey) -> Vec<(String, Vec<u8>)> {
+        let active = Keys::new(Prefix::Active)
+            .add("user".to_string())
+            .add(user.to_string());
+        let history = Keys::new(Prefix::History)
+            .add("user".to_string())
+            .add(Pubkey::new_unique().to_string());
+        let funding = Keys::new(Prefix::Funding)
+            .add(user.to_string())
+            .add("1700000000".to_string());
+        let dry_run = Keys::new(Prefix::DryRun)
+            .add(user.to_string())
+            .add(Pubkey::new_unique().to_string());
+        let f = funding_record(user);
+        let d = dry_run_record(user);
+        let funding_json = serde_json::json!({
+            "user": f.user,
+            "period": f.period,
+            "full_fund": -1_500_000.0,
+            "independent_fund": 250.4,
+            "positions": [{
+                "pubkey": f.positions[0].pubkey,
+                "market_account": f.positions[0].market_account,
+                "full": true,
+                "fund": -1_500_000.0,
+            }],
+        });
+        let dry_run_json = serde_json::json!({
+            "user": d.user,
+            "position": d.position,
+            "market_account": d.market_account,
+            "timestamp": d.timestamp,
+            "equity_ratio": d.equity_ratio,
+            "positions": [{
+                "pubkey": d.positions[0].pubkey,
+                "market_account": d.positions[0].market_account,
+                "price": 2_000_000_000.0,
+                "profit": -3_000_001.0,
+            }],
+        });
+        vec![
+            (
+                active.get_storage_key(),
+                serde_json::to_vec(&account(10, vec![1, 2, 3])).unwrap(),
+            ),
+            (
+                history.get_storage_key(),
+                serde_json::to_vec(&account(0, vec![4; 16])).unwrap(),
+            ),
+            (
+                funding.get_storage_key(),
+                serde_json::to_vec(&funding_json).unwrap(),
+            ),
+            (
+                dry_run.get_storage_key(),
+                serde_json::to_vec(&dry_run_json).unwrap(),
+            ),
+        ]
+    }
+
+    fn write_raw(storage: &Storage, values: &[(String, Vec<u8>)]) {
+        for (k, v) in values {
+            storage.db.insert(k.as_bytes(), v.clone()).unwrap();
+        }
+    }
+
+    // Check the values of legacy_values read back in the current format.
+    fn check_migrated(storage: &Storage, values: &[(String, Vec<u8>)]) {
+        let active: Account = get(storage, &values[0].0).unwrap();
+        assert_eq!(active, account(10, vec![1, 2, 3]));
+        let history: Account = get(storage, &values[1].0).unwrap();
+        assert_eq!(history, account(0, vec![4; 16]));
+        let funding: machine::FundingRecord = get(storage, &values[2].0).unwrap();
+        assert_eq!(funding.period, 1_700_000_000);
+        assert_eq!(funding.full_fund, com::Money::from_units(-1_500_000));
+        assert_eq!(funding.independent_fund, com::Money::from_units(250));
+        assert_eq!(funding.positions.len(), 1);
+        assert!(funding.positions[0].full);
+        assert_eq!(
+            funding.positions[0].fund,
+            com::Money::from_units(-1_500_000)
+        );
+        let dry_run: machine::DryRunRecord = get(storage, &values[3].0).unwrap();
+        assert_eq!(dry_run.timestamp, 1_700_000_123);
+        assert_eq!(dry_run.equity_ratio, 0.42);
+        assert_eq!(dry_run.positions.len(), 1);
+        assert_eq!(
+            dry_run.positions[0].price,
+            com::Money::from_units(2_000_000_000)
+        );
+        assert_eq!(
+            dry_run.positions[0].profit,
+            com::Money::from_units(-3_000_001)
+        );
+    }
+
+    #[test]
+    fn migrate_a_legacy_json_db() {
+        let dir = TempDir::new("migrate");
+        let storage = dir.open();
+        let values = legacy_values(Pubkey::new_unique());
+        write_raw(&storage, &values);
+        assert_eq!(storage.format_version().unwrap(), 0);
+
+        assert_eq!(storage.migrate().unwrap(), values.len());
+        assert_eq!(storage.format_version().unwrap(), FORMAT_VERSION);
+        check_migrated(&storage, &values);
+        // a migrated db has nothing left to do
+        assert_eq!(storage.migrate().unwrap(), 0);
+    }
+
+    #[test]
+    fn migrate_again_after_an_interrupted_migration() {
+        let dir = TempDir::new("migrate-again");
+        let storage = dir.open();
+        let values = legacy_values(Pubkey::new_unique());
+        write_raw(&storage, &values);
+        // the first values were rewritten before the migration stopped, the version was not
+        let migrated = vec![
+            (
+                values[0].0.clone(),
+                encode(&account(10, vec![1, 2, 3])).unwrap(),
+            ),
+            (
+                values[1].0.clone(),
+                encode(&account(0, vec![4; 16])).unwrap(),
+            ),
+        ];
+        write_raw(&storage, &migrated);
+        assert_eq!(storage.format_version().unwrap(), 0);
+
+        assert_eq!(storage.migrate().unwrap(), values.len() - migrated.len());
+        assert_eq!(storage.format_version().unwrap(), FORMAT_VERSION);
+        check_migrated(&storage, &values);
+    }
+
+    #[test]
+    fn new_rejects_other_format_versions() {
+        let dir = TempDir::new("versions");
+        let storage = dir.open();
+        // an empty db is created in the current version
+        assert!(storage.clone().checked().is_ok());
+
+        storage.meta.remove(FORMAT_VERSION_KEY).unwrap();
+        write_raw(&storage, &legacy_values(Pubkey::new_unique()));
+        let e = storage.clone().checked().err().unwrap();
+        assert!(e.to_string().contains("db migrate"), "{}", e);
+
+        storage
+            .meta
+            .insert(FORMAT_VERSION_KEY, vec![FORMAT_VERSION + 1])
+            .unwrap();
+        let e = storage.clone().checked().err().unwrap();
+        assert!(e.to_string().contains("newer"), "{}", e);
+    }
+
+    #[test]
+    fn encode_round_trips() {
+        for units in [0, 1, -1, 123_456_789, i64::MIN, i64::MAX] {
+            let m = com::Money::from_units(units);
+            let v = encode(&m).unwrap();
+            assert_eq!(v[0], ENCODING_VERSION);
+            assert_eq!(decode::<com::Money>(&v).unwrap(), m);
+        }
+
+        let user = Pubkey::new_unique();
+        let f = funding_record(user);
+        let back: machine::FundingRecord = decode(&encode(&f).unwrap()).unwrap();
+        assert_eq!(back.user, f.user);
+        assert_eq!(back.period, f.period);
+        assert_eq!(back.full_fund, f.full_fund);
+        assert_eq!(back.independent_fund, f.independent_fund);
+        assert_eq!(back.positions[0].pubkey, f.positions[0].pubkey);
+        assert_eq!(back.positions[0].fund, f.positions[0].fund);
+
+        let d = dry_run_record(user);
+        let back: machine::DryRunRecord = decode(&encode(&d).unwrap()).unwrap();
+        assert_eq!(back.position, d.position);
+        assert_eq!(back.market_account, d.market_account);
+        assert_eq!(back.timestamp, d.timestamp);
+        assert_eq!(back.equity_ratio, d.equity_ratio);
+        assert_eq!(back.positions[0].price, d.positions[0].price);
+        assert_eq!(back.positions[0].profit, d.positions[0].profit);
+
+        // values of another version are not read as the current one
+        let mut v = encode(&f).unwrap();
+        v[0] = ENCODING_VERSION + 1;
+        assert!(decode::<machine::FundingRecord>(&v).is_err());
+        assert!(decode::<machine::FundingRecord>(&[]).is_err());
+    }
+
+    // Close a position of the market at close_time, return the position.
+    fn close_position(
+        storage: &Storage,
+        market: Pubkey,
+        status: position::PositionStatus,
+        close_time: i64,
+    ) -> Pubkey {
+        let pubkey = Pubkey::new_unique();
+        let mut keys = position_keys(&Pubkey::new_unique(), &pubkey);
+        storage
+            .save_as_history(
+                &mut keys,
+                &position_account(market, Some(status)),
+                close_time,
+            )
+            .unwrap();
+        pubkey
+    }
+
+    fn positions(rs: Vec<(Pubkey, Account)>) -> Vec<Pubkey> {
+        rs.into_iter().map(|v| v.0).collect()
+    }
+
+    #[test]
+    fn history_range_scans() {
+        let dir = TempDir::new("ranges");
+        let storage = dir.open();
+        let btc = Pubkey::new_unique();
+        let eth = Pubkey::new_unique();
+        let normal = position::PositionStatus::NormalClosing;
+        let force = position::PositionStatus::ForceClosing;
+        // saved out of time order
+        let c = close_position(&storage, eth, position::PositionStatus::ForceClosing, 300);
+        let a = close_position(&storage, btc, position::PositionStatus::NormalClosing, 100);
+        let b = close_position(&storage, btc, position::PositionStatus::ForceClosing, 200);
+
+        let by_market = |market, status, from, to| {
+            positions(
+                storage
+                    .get_history_by_market(&market, status, from, to)
+                    .unwrap(),
+            )
+        };
+        assert_eq!(by_market(btc, None, 0, i64::MAX), vec![a, b]);
+        assert_eq!(by_market(btc, Some(&force), 0, i64::MAX), vec![b]);
+        assert_eq!(by_market(btc, Some(&normal), 0, i64::MAX), vec![a]);
+        assert_eq!(by_market(btc, None, 150, 300), vec![b]);
+        assert_eq!(by_market(eth, None, 0, 300), Vec::<Pubkey>::new());
+        assert_eq!(by_market(eth, None, 300, 301), vec![c]);
+
+        let by_time = |from, to| positions(storage.get_history_by_time(from, to).unwrap());
+        assert_eq!(by_time(0, i64::MAX), vec![a, b, c]);
+        // from is included, to is not
+        assert_eq!(by_time(100, 300), vec![a, b]);
+        assert_eq!(by_time(300, 100), Vec::<Pubkey>::new());
+
+        let by_status =
+            |status, from, to| positions(storage.get_history_by_status(status, from, to).unwrap());
+        assert_eq!(by_status(&force, 0, i64::MAX), vec![b, c]);
+        assert_eq!(by_status(&force, 250, i64::MAX), vec![c]);
+        assert_eq!(by_status(&normal, 0, i64::MAX), vec![a]);
+    }
+
+    #[test]
+    fn migrate_indexes_the_history() {
+        let dir = TempDir::new("index-history");
+        let storage = dir.open();
+        let market = Pubkey::new_unique();
+        // closed by a bot of this version, already indexed
+        let indexed = close_position(
+            &storage,
+            market,
+            position::PositionStatus::ForceClosing,
+            500,
+        );
+        // closed by a version 1 bot, saved without the indexes
+        let old = Pubkey::new_unique();
+        let mut keys = position_keys(&Pubkey::new_unique(), &old);
+        keys.set_prefix(Prefix::History);
+        let value = encode(&position_account(
+            market,
+            Some(position::PositionStatus::NormalClosing),
+        ))
+        .unwrap();
+        storage
+            .db
+            .insert(keys.get_storage_key().as_bytes(), value)
+            .unwrap();
+        storage.meta.insert(FORMAT_VERSION_KEY, vec![1]).unwrap();
+        assert!(storage.clone().checked().is_err());
+
+        assert_eq!(storage.migrate().unwrap(), 1);
+        assert_eq!(storage.format_version().unwrap(), FORMAT_VERSION);
+        // the unknown close time is 0
+        assert_eq!(
+            positions(storage.get_history_by_time(0, 1).unwrap()),
+            vec![old]
+        );
+        assert_eq!(
+            positions(
+                storage
+                    .get_history_by_market(&market, None, 0, i64::MAX)
+                    .unwrap()
+            ),
+            vec![old, indexed]
+        );
+        assert_eq!(
+            positions(
+                storage
+                    .get_history_by_status(&position::PositionStatus::NormalClosing, 0, i64::MAX)
+                    .unwrap()
+            ),
+            vec![old]
+        );
+    }
+
+    #[test]
+    fn short_history_index_key_is_an_error() {
+        let dir = TempDir::new("short-key");
+        let storage = dir.open();
+        let mut rs = Vec::new();
+        assert!(storage.push_history(&mut rs, &[1, 2, 3], b"key").is_err());
+        assert!(rs.is_empty());
+    }
+
+    #[test]
+    fn apply_writes_the_account_and_its_slot() {
+        let dir = TempDir::new("apply");
+        let storage = dir.open();
+        let pubkey = Pubkey::new_unique();
+        let keys = Keys::new(Prefix::Active)
+            .add("user".to_string())
+            .add(pubkey.to_string());
+        let acc = account(10, vec![1, 2, 3]);
+        let mut batch = WriteBatch::default();
+        batch.save_to_active(&keys, &acc).unwrap();
+        batch.save_slot(&pubkey, 42);
+        assert_eq!(batch.len(), 2);
+        assert_eq!(batch.slot_accounts(), &[pubkey]);
+        storage.apply(&batch).unwrap();
+
+        let saved: Option<Account> = get(&storage, &keys.get_storage_key());
+        assert_eq!(saved, Some(acc));
+        assert_eq!(storage.get_slot(&pubkey).unwrap(), Some(42));
+        assert_eq!(storage.get_slots().unwrap(), vec![(pubkey, 42)]);
+    }
+
+    #[test]
+    fn apply_moves_a_position_to_the_history() {
+        let dir = TempDir::new("history");
+        let storage = dir.open();
+        let user = Pubkey::new_unique();
+        let pubkey = Pubkey::new_unique();
+        let market = Pubkey::new_unique();
+        let keys = position_keys(&user, &pubkey);
+        let open = position_account(market, None);
+        let mut batch = WriteBatch::default();
+        batch.save_to_active(&keys, &open).unwrap();
+        batch.save_slot(&pubkey, 1);
+        storage.apply(&batch).unwrap();
+        assert!(!storage.is_history(&keys).unwrap());
+
+        let closed = position_account(market, Some(position::PositionStatus::NormalClosing));
+        let mut batch = WriteBatch::default();
+        let mut history_keys = keys.clone();
+        batch
+            .save_as_history(&mut history_keys, &closed, 100)
+            .unwrap();
+        batch.remove_slot(&pubkey);
+        assert!(batch.is_history(&keys));
+        storage.apply(&batch).unwrap();
+
+        let active: Option<Account> = get(&storage, &keys.get_storage_key());
+        assert_eq!(active, None);
+        let history: Option<Account> = get(&storage, &history_keys.get_storage_key());
+        assert_eq!(history, Some(closed));
+        assert!(storage.is_history(&keys).unwrap());
+        assert_eq!(storage.get_slot(&pubkey).unwrap(), None);
+        let by_market = storage
+            .get_history_by_market(&market, None, 0, i64::MAX)
+            .unwrap();
+        assert_eq!(by_market.len(), 1);
+        assert_eq!(by_market[0].0, pubkey);
+    }
 }
@@ -1,97 +1,430 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
 
 use {
-    crate::{com, config},
+    super::channel,
+    crate::{com, config, endpoint},
     anchor_client::solana_sdk::commitment_config::CommitmentConfig,
     anchor_client::solana_sdk::{account::Account, pubkey::Pubkey},
-    log::{debug, error, info},
-    solana_account_decoder::UiAccountEncoding,
+    bond::state::{market, position, user},
+    chrono::Utc,
+    dashmap::DashMap,
+    log::{debug, error, info, warn},
+    serde::{Deserialize, Serialize},
+    solana_account_decoder::{UiAccountEncoding, UiDataSliceConfig},
     solana_client::nonblocking::{pubsub_client, rpc_client},
     solana_client::rpc_config::{RpcAccountInfoConfig, RpcProgramAccountsConfig},
+    solana_client::rpc_filter::RpcFilterType,
     std::convert::TryFrom,
     tokio::{
         self,
-        sync::{mpsc, oneshot, watch},
+        sync::watch,
         task::JoinHandle,
+        time::{self, Duration, Instant},
     },
-    tokio_stream::StreamExt,
+    tokio_stream::{StreamExt, StreamMap},
 };
 
-pub struct SubAccount {
-    program_shutdown_tx: oneshot::Sender<()>,
-    price_shutdown_tx: watch::Sender<bool>,
-    pw: JoinHandle<anyhow::Result<()>>,
-    aw: JoinHandle<anyhow::Result<()>>,
+pub const RECONNECT_MIN_BACKOFF: Duration = Duration::from_secs(1);
+pub const RECONNECT_MAX_BACKOFF: Duration = Duration::from_secs(60);
+// How often a subscription checks if the primary endpoint has failed over or the heartbeat stopped.
+const FAILOVER_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+// The limit of accounts of a getMultipleAccounts request.
+const MAX_MULTIPLE_ACCOUNTS: usize = 100;
+
+// An account and the context slot it was read at.
+pub type AccountUpdate = (Pubkey, Account, u64);
+
+// Requests to the price account subscription.
+#[derive(Debug, Clone, Copy)]
+pub enum PriceSub {
+    Subscribe(Pubkey),
+    Unsubscribe(Pubkey),
 }
-impl SubAccount {
-    pub async fn new(
-        config: config::Config,
-        account_watch_tx: mpsc::UnboundedSender<(Pubkey, Account)>,
-        price_watch_tx: mpsc::UnboundedSender<(Pubkey, Account)>,
-        subscribe_rx: mpsc::UnboundedReceiver<Pubkey>,
-    ) -> Self {
-        let (program_shutdown_tx, program_shutdown_rx) = oneshot::channel::<()>();
-        let (price_shutdown_tx, price_shutdown_rx) = watch::channel(false);
-        // let pyth_price_program_pubkey = config.accounts.pyth_program_pubkey;
+
+// The state of a subscription.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SubHealth {
+    // false while reconnecting or resyncing
+    pub connected: bool,
+    // timestamp of the last message or state change
+    pub updated_at: i64,
+    pub reconnects: u64,
+}
+// key is the subscribed program or account
+pub type SharedSubHealth = Arc<DashMap<Pubkey, SubHealth>>;
+
+pub fn set_connected(health: &SharedSubHealth, pubkey: &Pubkey, connected: bool) {
+    let now = Utc::now().timestamp();
+    let mut h = health.entry(*pubkey).or_insert(SubHealth {
+        connected,
+        updated_at: now,
+        reconnects: 0,
+    });
+    if h.connected && !connected {
+        h.reconnects += 1;
+    }
+    h.connected = connected;
+    h.updated_at = now;
+}
+
+pub fn touch(health: &SharedSubHealth, pubkey: &Pubkey) {
+    if let Some(mut h) = health.get_mut(pubkey) {
+        h.updated_at = Utc::now().timestamp();
+    }
+}
+
+// What a subscription keeps across its reconnects.
+pub struct Reconnect {
+    pub backoff: Duration,
+    // fetch the accounts again, the updates while disconnected are missed
+    pub resync: bool,
+    // a connection without any heartbeat for longer is reconnected
+    pub max_silence: Duration,
+}
+
+// The liveness of a connection. A quiet account is no sign of a dead socket, so the connection
+// is checked on a signal the server sends all the time: the slot notifications, or the pings
+// of a geyser stream. The staleness of each price is left to the price guard.
+pub struct Heartbeat {
+    last: Instant,
+}
+
+impl Heartbeat {
+    pub fn new() -> Self {
+        Self {
+            last: Instant::now(),
+        }
+    }
+
+    pub fn beat(&mut self) {
+        self.last = Instant::now();
+    }
+
+    // Return the reason to reconnect if there was no heartbeat for longer than max_silence,
+    // a zero max_silence disables the check.
+    pub fn check(&self, max_silence: Duration) -> Option<String> {
+        let silence = self.last.elapsed();
+        if max_silence.is_zero() || silence <= max_silence {
+            return None;
+        }
+        Some(format!("no heartbeat for {:?}", silence))
+    }
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Reconnect {
+    pub fn new(max_silence: Duration) -> Self {
+        Self {
+            backoff: RECONNECT_MIN_BACKOFF,
+            resync: false,
+            max_silence,
+        }
+    }
+
+    // Called once a subscription is established, the next failure starts from the min backoff.
+    pub fn established(&mut self) {
+        self.backoff = RECONNECT_MIN_BACKOFF;
+    }
+
+    // Return how long to wait before reconnecting, a failover reconnects at once.
+    pub fn next_delay(&mut self, failover: bool) -> Duration {
+        self.resync = true;
+        if failover {
+            return Duration::ZERO;
+        }
+        let delay = self.backoff;
+        self.backoff = (self.backoff * 2).min(RECONNECT_MAX_BACKOFF);
+        delay
+    }
+}
+
+// Why a subscription stopped.
+pub enum SubExit {
+    Shutdown,
+    ChannelClosed,
+    Disconnected(String),
+    // the primary endpoint changed, reconnect without waiting
+    Failover(String),
+}
+
+// The channels an account source feeds.
+pub struct SourceChannels {
+    pub account_watch_tx: channel::Sender<AccountUpdate>,
+    pub price_watch_tx: channel::LatestSender,
+    pub subscribe_rx: channel::UnboundedReceiver<PriceSub>,
+    pub health: SharedSubHealth,
+}
+
+// Where the program and price account updates come from.
+pub trait AccountSource: Send + Sync {
+    fn name(&self) -> &'static str;
+    // Spawn the tasks streaming the program accounts and the subscribed price accounts
+    // to the watch channels, they exit when shutdown_rx changes.
+    fn spawn(
+        &self,
+        channels: SourceChannels,
+        shutdown_rx: watch::Receiver<bool>,
+    ) -> Vec<JoinHandle<anyhow::Result<()>>>;
+}
+
+pub fn new_account_source(
+    config: &config::Config,
+    endpoints: endpoint::SharedEndpointPool,
+) -> anyhow::Result<Box<dyn AccountSource>> {
+    match config.account_source.kind {
+        config::AccountSourceKind::Pubsub => Ok(Box::new(PubsubSource::new(
+            endpoints,
+            Duration::from_secs(config.account_source.max_silence_seconds),
+        ))),
+        #[cfg(feature = "geyser")]
+        config::AccountSourceKind::Geyser => Ok(Box::new(super::geyser::GeyserSource::new(
+            config.clone(),
+            endpoints,
+        ))),
+        #[cfg(not(feature = "geyser"))]
+        config::AccountSourceKind::Geyser => Err(com::CliError::Unknown(
+            "the geyser account source needs the geyser feature".to_string(),
+        )
+        .into()),
+    }
+}
+
+// The websocket subscriptions of the primary rpc endpoint.
+pub struct PubsubSource {
+    endpoints: endpoint::SharedEndpointPool,
+    // a subscription without any message for longer is reconnected
+    max_silence: Duration,
+}
+
+impl PubsubSource {
+    pub fn new(endpoints: endpoint::SharedEndpointPool, max_silence: Duration) -> Self {
         Self {
-            program_shutdown_tx,
-            price_shutdown_tx,
-            aw: tokio::spawn(subscribe_program_accounts(
-                config.clone(),
+            endpoints,
+            max_silence,
+        }
+    }
+}
+
+impl AccountSource for PubsubSource {
+    fn name(&self) -> &'static str {
+        "pubsub"
+    }
+
+    fn spawn(
+        &self,
+        channels: SourceChannels,
+        shutdown_rx: watch::Receiver<bool>,
+    ) -> Vec<JoinHandle<anyhow::Result<()>>> {
+        vec![
+            tokio::spawn(subscribe_program_accounts(
+                self.endpoints.clone(),
                 com::id(),
-                program_shutdown_rx,
-                account_watch_tx,
+                shutdown_rx.clone(),
+                channels.account_watch_tx,
+                channels.health.clone(),
+                self.max_silence,
             )),
-            pw: tokio::spawn(subscribe_price_accounts(
-                config.clone(),
-                subscribe_rx,
-                price_shutdown_rx.clone(),
-                price_watch_tx.clone(),
+            tokio::spawn(subscribe_price_accounts(
+                self.endpoints.clone(),
+                channels.subscribe_rx,
+                shutdown_rx,
+                channels.price_watch_tx,
+                channels.health,
+                self.max_silence,
             )),
+        ]
+    }
+}
+
+pub struct SubAccount {
+    shutdown_tx: watch::Sender<bool>,
+    tasks: Vec<JoinHandle<anyhow::Result<()>>>,
+}
+impl SubAccount {
+    pub async fn new(source: &dyn AccountSource, channels: SourceChannels) -> Self {
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        info!("start account source: {}", source.name());
+        Self {
+            shutdown_tx,
+            tasks: source.spawn(channels, shutdown_rx),
         }
     }
     pub async fn shutdown(self) {
-        let _ = self.program_shutdown_tx.send(());
-        let _ = self.aw.await;
-        let _ = self.price_shutdown_tx.send(true);
-        let _ = self.pw.await;
+        let _ = self.shutdown_tx.send(true);
+        for t in self.tasks {
+            let _ = t.await;
+        }
     }
 
     pub async fn get_all_program_accounts(
         &self,
-        config: config::Config,
-        watch_tx: mpsc::UnboundedSender<(Pubkey, Account)>,
+        endpoints: &endpoint::EndpointPool,
+        watch_tx: channel::Sender<AccountUpdate>,
     ) -> anyhow::Result<()> {
-        let client = rpc_client::RpcClient::new(config.cluster.url().to_string());
-        let id = com::id();
-        let accounts = client.get_program_accounts(&id).await?;
-        for a in accounts {
-            debug!("get all program accounts for rpc node:{}", a.0);
-            match watch_tx.send(a) {
-                Ok(()) => {}
-                Err(e) => {
-                    error!("message channel error:{},sub program exit.", e);
-                    break;
+        get_all_program_accounts(endpoints, &com::id(), &watch_tx).await
+    }
+}
+
+// List the pubkeys of the program accounts of a data size, without their data.
+async fn get_program_account_keys(
+    client: &rpc_client::RpcClient,
+    program_pubkey: &Pubkey,
+    data_size: usize,
+) -> anyhow::Result<Vec<Pubkey>> {
+    let accounts = client
+        .get_program_accounts_with_config(
+            program_pubkey,
+            RpcProgramAccountsConfig {
+                filters: Some(vec![RpcFilterType::DataSize(data_size as u64)]),
+                account_config: RpcAccountInfoConfig {
+                    encoding: Some(UiAccountEncoding::Base64),
+                    data_slice: Some(UiDataSliceConfig {
+                        offset: 0,
+                        length: 0,
+                    }),
+                    commitment: Some(CommitmentConfig::confirmed()),
+                    min_context_slot: None,
+                },
+                with_context: None,
+            },
+        )
+        .await?;
+    Ok(accounts.into_iter().map(|(pubkey, _)| pubkey).collect())
+}
+
+// Fetch a snapshot of the markets, users and positions of the program. Each kind is
+// filtered by its data size, the pubkeys are listed first without data and then the
+// accounts are fetched in batches.
+pub async fn get_all_program_accounts(
+    endpoints: &endpoint::EndpointPool,
+    program_pubkey: &Pubkey,
+    watch_tx: &channel::Sender<AccountUpdate>,
+) -> anyhow::Result<()> {
+    let client = endpoints.rpc();
+    // markets first, so the positions can find their market when they are loaded
+    let kinds = [
+        ("market", market::Market::LEN),
+        ("user", user::UserAccount::LEN),
+        ("position", position::Position::LEN),
+    ];
+    for (name, len) in kinds {
+        let pubkeys = get_program_account_keys(&client, program_pubkey, len + 8).await?;
+        let total = pubkeys.len();
+        info!("start loading {} {} accounts ...", total, name);
+        let mut loaded: usize = 0;
+        for chunk in pubkeys.chunks(MAX_MULTIPLE_ACCOUNTS) {
+            let response = client
+                .get_multiple_accounts_with_commitment(chunk, CommitmentConfig::confirmed())
+                .await?;
+            let slot = response.context.slot;
+            for (pubkey, account) in chunk.iter().zip(response.value) {
+                let account = match account {
+                    Some(a) => a,
+                    // closed between the two requests
+                    None => continue,
+                };
+                debug!("get program account for rpc node:{}", pubkey);
+                if let Err(e) = watch_tx.send((*pubkey, account, slot)).await {
+                    return Err(anyhow::anyhow!("message channel error:{}", e));
                 }
+                loaded += 1;
             }
+            info!("loaded {}/{} {} accounts", loaded, total, name);
         }
-        Ok(())
     }
+    Ok(())
+}
+
+// Return the reason to reconnect if the primary endpoint is no longer the connected one.
+fn check_failover(
+    endpoints: &endpoint::EndpointPool,
+    connected: &endpoint::Endpoint,
+) -> Option<String> {
+    let primary = endpoints.primary();
+    if primary.rpc_url == connected.rpc_url {
+        return None;
+    }
+    Some(format!(
+        "primary endpoint failed over from {} to {}",
+        connected.rpc_url, primary.rpc_url
+    ))
 }
 
+// Keep the program subscription alive, reconnect with backoff and fetch all the program accounts
+// again after each reconnect to fill the updates missed while disconnected.
+// The backoff is reset once a subscription is established, a failover reconnects at once.
 async fn subscribe_program_accounts(
-    config: config::Config,
+    endpoints: endpoint::SharedEndpointPool,
     program_pubkey: Pubkey,
-    mut shutdown_rx: oneshot::Receiver<()>,
-    watch_tx: mpsc::UnboundedSender<(Pubkey, Account)>,
+    mut shutdown_rx: watch::Receiver<bool>,
+    watch_tx: channel::Sender<AccountUpdate>,
+    health: SharedSubHealth,
+    max_silence: Duration,
 ) -> anyhow::Result<()> {
-    let sol_sub_client = pubsub_client::PubsubClient::new(config.cluster.ws_url())
-        .await
-        .map_err(|e| {
+    let mut reconnect = Reconnect::new(max_silence);
+    loop {
+        let exit = run_program_subscription(
+            &endpoints,
+            &program_pubkey,
+            &mut shutdown_rx,
+            &watch_tx,
+            &health,
+            &mut reconnect,
+        )
+        .await;
+        let (e, failover) = match exit {
+            SubExit::Shutdown => {
+                info!("got shutdown signal, account sub exit.");
+                break;
+            }
+            SubExit::ChannelClosed => break,
+            SubExit::Disconnected(e) => (e, false),
+            SubExit::Failover(e) => (e, true),
+        };
+        let delay = reconnect.next_delay(failover);
+        set_connected(&health, &program_pubkey, false);
+        warn!(
+            "program account subscription lost: {}, reconnect in {:?}",
+            e, delay
+        );
+        tokio::select! {
+            _ = time::sleep(delay) => {}
+            _ = shutdown_rx.changed() => {
+                info!("got shutdown signal, account sub exit.");
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn run_program_subscription(
+    endpoints: &endpoint::EndpointPool,
+    program_pubkey: &Pubkey,
+    shutdown_rx: &mut watch::Receiver<bool>,
+    watch_tx: &channel::Sender<AccountUpdate>,
+    health: &SharedSubHealth,
+    reconnect: &mut Reconnect,
+) -> SubExit {
+    let endpoint = endpoints.primary();
+    let sol_sub_client = match pubsub_client::PubsubClient::new(&endpoint.ws_url).await {
+        Ok(c) => c,
+        Err(e) => {
             debug!("{:#?}", e);
-            com::CliError::SubscriptionAccountFailed(e.to_string())
-        })?;
-    info!("start program account subscription ...");
+            endpoints.report_error(&endpoint.rpc_url, &e.to_string());
+            return SubExit::Disconnected(e.to_string());
+        }
+    };
+    info!(
+        "start program account subscription of {} ...",
+        endpoint.ws_url
+    );
     let rpc_config = RpcProgramAccountsConfig {
         filters: None,
         account_config: RpcAccountInfoConfig {
@@ -102,17 +435,36 @@ async fn subscribe_program_accounts(
         },
         with_context: None,
     };
-    let (mut s, _r) = sol_sub_client
-        .program_subscribe(&program_pubkey, Some(rpc_config))
+    let (mut s, _r) = match sol_sub_client
+        .program_subscribe(program_pubkey, Some(rpc_config))
         .await
-        .map_err(|e| com::CliError::SubscriptionAccountFailed(e.to_string()))?;
+    {
+        Ok(v) => v,
+        Err(e) => return SubExit::Disconnected(e.to_string()),
+    };
     let mut s = s.as_mut();
+    let (mut slots, _slot_unsubscribe) = match sol_sub_client.slot_subscribe().await {
+        Ok(v) => v,
+        Err(e) => return SubExit::Disconnected(e.to_string()),
+    };
+    // Subscribe first, so no update is lost between the fetch and the subscription.
+    if reconnect.resync {
+        info!("resync all program accounts after reconnect ...");
+        if let Err(e) = get_all_program_accounts(endpoints, program_pubkey, watch_tx).await {
+            return SubExit::Disconnected(format!("resync program accounts error: {}", e));
+        }
+    }
+    set_connected(health, program_pubkey, true);
+    reconnect.established();
+    let mut failover = time::interval(FAILOVER_CHECK_INTERVAL);
+    let mut heartbeat = Heartbeat::new();
 
     loop {
         tokio::select! {
             response = s.next() => {
                 match response {
                     Some(i_account)=>{
+                        touch(health, program_pubkey);
                         let pda_pubkey = Pubkey::try_from(i_account.value.pubkey.as_str());
                         let pda_account:Option<Account> = i_account.value.account.decode();
                         match pda_account {
@@ -120,13 +472,13 @@ async fn subscribe_program_accounts(
                                 debug!("got account: {:?} data: {:#?},len:{}",pda_pubkey,account,account.data.len());
                                 match pda_pubkey {
                                     Ok(pubkey)=>{
-                                        match watch_tx.send((pubkey,account)) {
+                                        match watch_tx.send((pubkey,account,i_account.context.slot)).await {
                                             Ok(())=>{
                                                 debug!("send {:?} to account watch success!",pda_pubkey);
                                             }
                                             Err(e)=>{
                                                 error!("message channel error:{},sub program exit.",e);
-                                                break;
+                                                return SubExit::ChannelClosed;
                                             }
                                         }
                                     }
@@ -141,127 +493,278 @@ async fn subscribe_program_accounts(
                         }
                     }
                     None=>{
-                        info!("message channel close");
-                        break;
+                        return SubExit::Disconnected("message channel close".to_string());
                     }
                 }
             }
-            _ = (&mut shutdown_rx) => {
-                info!("got shutdown signal, account sub exit.");
-                break;
+            slot = slots.next() => {
+                if slot.is_none() {
+                    return SubExit::Disconnected("slot stream closed".to_string());
+                }
+                heartbeat.beat();
+            }
+            _ = failover.tick() => {
+                if let Some(e) = check_failover(endpoints, &endpoint) {
+                    return SubExit::Failover(e);
+                }
+                if let Some(e) = heartbeat.check(reconnect.max_silence) {
+                    return SubExit::Disconnected(e);
+                }
+            }
+            _ = shutdown_rx.changed() => {
+                return SubExit::Shutdown;
             },
         }
     }
-    Ok(())
 }
 
+// Keep one websocket for all the price accounts, reconnect with backoff and fetch the accounts
+// again after each reconnect.
 async fn subscribe_price_accounts(
-    config: config::Config,
-    mut subscribe_rx: mpsc::UnboundedReceiver<Pubkey>,
+    endpoints: endpoint::SharedEndpointPool,
+    mut subscribe_rx: channel::UnboundedReceiver<PriceSub>,
     mut shutdown_rx: watch::Receiver<bool>,
-    watch_tx: mpsc::UnboundedSender<(Pubkey, Account)>,
+    watch_tx: channel::LatestSender,
+    health: SharedSubHealth,
+    max_silence: Duration,
 ) -> anyhow::Result<()> {
     info!("start price account subscription ...");
     let mut price_account: HashSet<Pubkey> = HashSet::new();
-    let mut sub_tasks: Vec<JoinHandle<anyhow::Result<()>>> = Vec::new();
+    let mut reconnect = Reconnect::new(max_silence);
     loop {
+        let exit = run_price_subscription(
+            &endpoints,
+            &mut price_account,
+            &mut subscribe_rx,
+            &mut shutdown_rx,
+            &watch_tx,
+            &health,
+            &mut reconnect,
+        )
+        .await;
+        let (e, failover) = match exit {
+            SubExit::Shutdown => {
+                info!("got shutdown signal, price accounts sub exit.");
+                break;
+            }
+            SubExit::ChannelClosed => break,
+            SubExit::Disconnected(e) => (e, false),
+            SubExit::Failover(e) => (e, true),
+        };
+        let delay = reconnect.next_delay(failover);
+        for pubkey in &price_account {
+            set_connected(&health, pubkey, false);
+        }
+        warn!(
+            "price account subscription lost: {}, reconnect in {:?}",
+            e, delay
+        );
         tokio::select! {
+            _ = time::sleep(delay) => {}
             _ = shutdown_rx.changed() => {
                 info!("got shutdown signal, price accounts sub exit.");
-                for t in sub_tasks{
-                    match t.await {
-                        Ok(_)=>{
-                            info!("Close price account sub task success!");
-                        }
-                        Err(e)=>{
-                            error!("Close price account sub task error: {}", e);
-                        }
-                    }
-                }
                 break;
-            },
-            price_pubkey = subscribe_rx.recv() => {
-                match price_pubkey {
-                    Some(pubkey)=>{
-                        if !price_account.insert(pubkey) {
-                            sub_tasks.push(tokio::spawn(subscribe_one_price_account(
-                                config.clone(),
-                                pubkey,
-                                shutdown_rx.clone(),
-                                watch_tx.clone(),
-                            )));
-                        }
-                    }
-                    None=>{
-                        debug!("Sub accounts continue,got none pubkey");
-                    }
-                }
             }
         }
     }
     Ok(())
 }
 
-async fn subscribe_one_price_account(
-    config: config::Config,
-    pubkey: Pubkey,
-    mut shutdown_rx: watch::Receiver<bool>,
-    watch_tx: mpsc::UnboundedSender<(Pubkey, Account)>,
-) -> anyhow::Result<()> {
-    let sol_sub_client = pubsub_client::PubsubClient::new(config.cluster.ws_url())
-        .await
-        .map_err(|e| {
+async fn run_price_subscription(
+    endpoints: &endpoint::EndpointPool,
+    price_account: &mut HashSet<Pubkey>,
+    subscribe_rx: &mut channel::UnboundedReceiver<PriceSub>,
+    shutdown_rx: &mut watch::Receiver<bool>,
+    watch_tx: &channel::LatestSender,
+    health: &SharedSubHealth,
+    reconnect: &mut Reconnect,
+) -> SubExit {
+    let endpoint = endpoints.primary();
+    let sol_sub_client = match pubsub_client::PubsubClient::new(&endpoint.ws_url).await {
+        Ok(c) => c,
+        Err(e) => {
             debug!("{:#?}", e);
-            com::CliError::SubscriptionAccountFailed(e.to_string())
-        })?;
-    info!("start pyth price account {} subscription ...", pubkey);
+            endpoints.report_error(&endpoint.rpc_url, &e.to_string());
+            return SubExit::Disconnected(e.to_string());
+        }
+    };
     let rpc_config = RpcAccountInfoConfig {
         encoding: Some(UiAccountEncoding::Base64Zstd),
         commitment: Some(CommitmentConfig::processed()),
         data_slice: None,
         min_context_slot: None,
     };
-    let (mut s, _r) = sol_sub_client
-        .account_subscribe(&pubkey, Some(rpc_config))
-        .await
-        .map_err(|e| com::CliError::SubscriptionAccountFailed(e.to_string()))?;
-    let mut s = s.as_mut();
+    let (mut slots, _slot_unsubscribe) = match sol_sub_client.slot_subscribe().await {
+        Ok(v) => v,
+        Err(e) => return SubExit::Disconnected(e.to_string()),
+    };
+    let mut streams = StreamMap::new();
+    let mut unsubscribes = HashMap::new();
+    for pubkey in price_account.iter() {
+        match sol_sub_client
+            .account_subscribe(pubkey, Some(rpc_config.clone()))
+            .await
+        {
+            Ok((s, unsubscribe)) => {
+                streams.insert(*pubkey, s);
+                unsubscribes.insert(*pubkey, unsubscribe);
+            }
+            Err(e) => return SubExit::Disconnected(e.to_string()),
+        }
+    }
+    // Subscribe first, so no update is lost between the fetch and the subscription.
+    if reconnect.resync && !price_account.is_empty() {
+        info!(
+            "resync {} price accounts after reconnect ...",
+            price_account.len()
+        );
+        let client = endpoint.rpc.clone();
+        let keys: Vec<Pubkey> = price_account.iter().copied().collect();
+        for chunk in keys.chunks(MAX_MULTIPLE_ACCOUNTS) {
+            let response = match client
+                .get_multiple_accounts_with_commitment(chunk, CommitmentConfig::processed())
+                .await
+            {
+                Ok(v) => v,
+                Err(e) => {
+                    return SubExit::Disconnected(format!("resync price accounts error: {}", e));
+                }
+            };
+            let slot = response.context.slot;
+            for (pubkey, account) in chunk.iter().zip(response.value) {
+                if let Some(account) = account {
+                    watch_tx.send((*pubkey, account, slot));
+                }
+            }
+        }
+    }
+    for pubkey in price_account.iter() {
+        set_connected(health, pubkey, true);
+    }
+    reconnect.established();
+    info!(
+        "start price account subscription of {} accounts ...",
+        price_account.len()
+    );
+    let mut failover = time::interval(FAILOVER_CHECK_INTERVAL);
+    let mut heartbeat = Heartbeat::new();
 
     loop {
         tokio::select! {
-            response = s.next() => {
-                match response {
-                    Some(i_account)=>{
-                        let pda_account:Option<Account> = i_account.value.decode();
-                        match pda_account {
-                            Some(account)=>{
-                                debug!("got price account: {:?} data: {:#?},len:{}",pubkey,account,account.data.len());
-                                match watch_tx.send((pubkey,account)) {
-                                    Ok(())=>{
-                                        debug!("send {:?} to price account watch success!",pubkey);
-                                    }
-                                    Err(e)=>{
-                                        error!("message channel error:{},price account sub program exit.",e);
-                                        break;
-                                    }
+            r = streams.next(), if !streams.is_empty() => {
+                let (pubkey, response) = match r {
+                    Some(v) => v,
+                    None => return SubExit::Disconnected("price account stream closed".to_string()),
+                };
+                touch(health, &pubkey);
+                let pda_account:Option<Account> = response.value.decode();
+                match pda_account {
+                    Some(account)=>{
+                        debug!("got price account: {:?} data: {:#?},len:{}",pubkey,account,account.data.len());
+                        watch_tx.send((pubkey,account,response.context.slot));
+                        debug!("send {:?} to price account watch success!",pubkey);
+                    }
+                    None=>{
+                        error!("Can not decode price account,got None");
+                    }
+                }
+            }
+            r = subscribe_rx.recv() => {
+                match r {
+                    Some(PriceSub::Subscribe(pubkey))=>{
+                        if price_account.insert(pubkey) {
+                            // A failed account stays in the set and is subscribed after the reconnect.
+                            match sol_sub_client
+                                .account_subscribe(&pubkey, Some(rpc_config.clone()))
+                                .await
+                            {
+                                Ok((s, unsubscribe)) => {
+                                    streams.insert(pubkey, s);
+                                    unsubscribes.insert(pubkey, unsubscribe);
+                                    set_connected(health, &pubkey, true);
+                                    info!("subscribe price account {}", pubkey);
                                 }
+                                Err(e) => return SubExit::Disconnected(e.to_string()),
                             }
-                            None=>{
-                                error!("Can not decode price account,got None");
+                        }
+                    }
+                    Some(PriceSub::Unsubscribe(pubkey))=>{
+                        if price_account.remove(&pubkey) {
+                            streams.remove(&pubkey);
+                            if let Some(unsubscribe) = unsubscribes.remove(&pubkey) {
+                                unsubscribe().await;
                             }
+                            health.remove(&pubkey);
+                            info!("unsubscribe price account {}", pubkey);
                         }
                     }
                     None=>{
-                        info!("message channel close");
-                        break;
+                        debug!("price account sub channel closed");
+                        return SubExit::ChannelClosed;
                     }
                 }
             }
+            slot = slots.next() => {
+                if slot.is_none() {
+                    return SubExit::Disconnected("slot stream closed".to_string());
+                }
+                heartbeat.beat();
+            }
+            _ = failover.tick() => {
+                if let Some(e) = check_failover(endpoints, &endpoint) {
+                    return SubExit::Failover(e);
+                }
+                if let Some(e) = heartbeat.check(reconnect.max_silence) {
+                    return SubExit::Disconnected(e);
+                }
+            }
             _ = shutdown_rx.changed() => {
-                info!("got shutdown signal,price account {} sub exit.",pubkey);
-                break;
+                return SubExit::Shutdown;
             },
         }
+        // The streams end together when the websocket is closed.
+        if streams.len() < price_account.len() {
+            return SubExit::Disconnected("price account stream closed".to_string());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_grows_until_established() {
+        let mut r = Reconnect::new(Duration::ZERO);
+        assert_eq!(r.next_delay(false), RECONNECT_MIN_BACKOFF);
+        assert_eq!(r.next_delay(false), RECONNECT_MIN_BACKOFF * 2);
+        assert!(r.resync);
+        r.established();
+        assert_eq!(r.next_delay(false), RECONNECT_MIN_BACKOFF);
+        for _ in 0..16 {
+            r.next_delay(false);
+        }
+        assert_eq!(r.next_delay(false), RECONNECT_MAX_BACKOFF);
+    }
+
+    #[test]
+    fn failover_reconnects_at_once() {
+        let mut r = Reconnect::new(Duration::ZERO);
+        r.next_delay(false);
+        assert_eq!(r.next_delay(true), Duration::ZERO);
+        // a failover does not grow the backoff of the next failure
+        assert_eq!(r.next_delay(false), RECONNECT_MIN_BACKOFF * 2);
+    }
+
+    #[test]
+    fn silent_connection_misses_the_heartbeat() {
+        let max_silence = Duration::from_secs(60);
+        let mut h = Heartbeat::new();
+        assert!(h.check(max_silence).is_none());
+        h.last -= Duration::from_secs(61);
+        assert!(h.check(max_silence).is_some());
+        assert!(h.check(Duration::ZERO).is_none());
+        h.beat();
+        assert!(h.check(max_silence).is_none());
     }
-    Ok(())
 }
@@ -0,0 +1,458 @@
+use crate::{client, com, config};
+use anchor_client::solana_sdk::{pubkey::Pubkey, signature::Signature};
+use dashmap::DashMap;
+use log::{debug, error, info, warn};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use tokio::time::{self, Duration, Instant};
+
+// Give up a burst after this many fresh blockhashes.
+const MAX_SEND_ATTEMPTS: u32 = 5;
+// Give up a burst when this many blockhash fetches fail in a row, nothing was sent for them.
+const MAX_BLOCKHASH_ERRORS: u32 = 20;
+const CONFIRM_POLL_INTERVAL: Duration = Duration::from_millis(800);
+// A confirmed position is not burst again before the closed account arrives.
+const CONFIRMED_COOLDOWN: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BurstStatus {
+    InFlight,
+    Confirmed,
+}
+
+#[derive(Debug, Clone)]
+pub struct InFlight {
+    pub user_account: Pubkey,
+    pub status: BurstStatus,
+    pub attempts: u32,
+    pub signatures: Vec<Signature>,
+    pub updated_at: Instant,
+}
+
+// key is position account
+type DmInFlight = DashMap<Pubkey, InFlight>;
+
+// Send burst transactions and track them until they are confirmed or expired.
+// A position is only submitted once while its close is pending.
+#[derive(Clone)]
+pub struct Submitter {
+    config: config::Config,
+    ctx: com::SharedBotContext,
+    in_flight: Arc<DmInFlight>,
+    // set on shutdown, the transactions sent are confirmed but not sent again
+    stopping: Arc<AtomicBool>,
+}
+
+impl Submitter {
+    pub fn new(config: config::Config, ctx: com::SharedBotContext) -> Self {
+        Self {
+            config,
+            ctx,
+            in_flight: Arc::new(DashMap::new()),
+            stopping: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    // Stop submitting and re-sending, the transactions in flight are still confirmed.
+    pub fn shutdown(&self) {
+        self.stopping.store(true, Ordering::Release);
+    }
+
+    pub fn is_pending(&self, position: &Pubkey) -> bool {
+        match self.in_flight.get(position) {
+            Some(v) => {
+                v.status == BurstStatus::InFlight || v.updated_at.elapsed() < CONFIRMED_COOLDOWN
+            }
+            None => false,
+        }
+    }
+
+    // The status of the user's bursts still pending, InFlight if any of them is not confirmed.
+    pub fn user_pending(&self, user: &Pubkey) -> Option<BurstStatus> {
+        let mut status = None;
+        for v in self.in_flight.iter().filter(|v| v.user_account == *user) {
+            if v.status == BurstStatus::InFlight {
+                return Some(BurstStatus::InFlight);
+            }
+            if v.updated_at.elapsed() < CONFIRMED_COOLDOWN {
+                status = Some(BurstStatus::Confirmed);
+            }
+        }
+        status
+    }
+
+    pub fn ctx(&self) -> &com::SharedBotContext {
+        &self.ctx
+    }
+
+    pub fn in_flight_len(&self) -> usize {
+        self.in_flight
+            .iter()
+            .filter(|v| v.status == BurstStatus::InFlight)
+            .count()
+    }
+
+    // Return false if the position is already pending.
+    pub fn submit(&self, keys: client::BurstAccounts, max_unit_price: u64) -> bool {
+        if self.stopping.load(Ordering::Acquire) {
+            debug!(
+                "submitter is stopping, skip burst position {}",
+                keys.position_account
+            );
+            return false;
+        }
+        if !self.register(&keys) {
+            return false;
+        }
+        tokio::spawn(track_burst(
+            self.config.priority_fee.clone(),
+            self.ctx.clone(),
+            self.in_flight.clone(),
+            self.stopping.clone(),
+            keys,
+            max_unit_price,
+        ));
+        true
+    }
+
+    // Track the position as in flight, return false if it is already pending.
+    fn register(&self, keys: &client::BurstAccounts) -> bool {
+        self.in_flight.retain(|_, v| {
+            v.status == BurstStatus::InFlight || v.updated_at.elapsed() < CONFIRMED_COOLDOWN
+        });
+        if self.is_pending(&keys.position_account) {
+            debug!(
+                "burst position {} is pending, skip it",
+                keys.position_account
+            );
+            return false;
+        }
+        self.in_flight.insert(
+            keys.position_account,
+            InFlight {
+                user_account: keys.user_account,
+                status: BurstStatus::InFlight,
+                attempts: 0,
+                signatures: Vec::new(),
+                updated_at: Instant::now(),
+            },
+        );
+        true
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Resend {
+    Send,
+    Stopping,
+    Exhausted,
+}
+
+// The sends of one burst. Only a transaction actually handed to an endpoint is an attempt,
+// a failed blockhash fetch is counted apart so a flaky rpc does not use up the attempts.
+#[derive(Debug, Default)]
+struct Attempts {
+    sent: u32,
+    blockhash_errors: u32,
+}
+
+impl Attempts {
+    fn next(&self, stopping: bool) -> Resend {
+        if stopping {
+            Resend::Stopping
+        } else if self.sent >= MAX_SEND_ATTEMPTS || self.blockhash_errors >= MAX_BLOCKHASH_ERRORS {
+            Resend::Exhausted
+        } else {
+            Resend::Send
+        }
+    }
+
+    fn blockhash_failed(&mut self) {
+        self.blockhash_errors += 1;
+    }
+
+    // Return the number of the attempt being sent.
+    fn send(&mut self) -> u32 {
+        self.blockhash_errors = 0;
+        self.sent += 1;
+        self.sent
+    }
+}
+
+// A blockhash is no longer accepted once the chain is past its last valid block height.
+fn is_expired(block_height: u64, last_valid_block_height: u64) -> bool {
+    block_height > last_valid_block_height
+}
+
+async fn get_compute_budget(
+    fee: &config::PriorityFeeConfig,
+    ctx: &com::BotContext,
+    keys: &client::BurstAccounts,
+    max_unit_price: u64,
+) -> client::ComputeBudget {
+    let mut unit_price = fee.compute_unit_price;
+    if fee.dynamic {
+        match client::get_recent_prioritization_fee(
+            ctx,
+            &[
+                keys.market_account,
+                keys.position_account,
+                keys.user_account,
+            ],
+        )
+        .await
+        {
+            Ok(p) => {
+                unit_price = unit_price.max(p);
+            }
+            Err(e) => {
+                error!("get recent prioritization fee error:{}", e);
+            }
+        }
+    }
+    client::ComputeBudget {
+        unit_limit: fee.compute_unit_limit,
+        unit_price: unit_price.min(max_unit_price),
+    }
+}
+
+async fn track_burst(
+    fee: config::PriorityFeeConfig,
+    ctx: com::SharedBotContext,
+    in_flight: Arc<DmInFlight>,
+    stopping: Arc<AtomicBool>,
+    keys: client::BurstAccounts,
+    max_unit_price: u64,
+) {
+    let position = keys.position_account;
+    let commitment = ctx.commitment;
+    let mut attempts = Attempts::default();
+    loop {
+        match attempts.next(stopping.load(Ordering::Acquire)) {
+            Resend::Send => {}
+            Resend::Stopping => {
+                warn!(
+                    "burst position {} not confirmed, do not send it again on shutdown",
+                    position
+                );
+                in_flight.remove(&position);
+                return;
+            }
+            Resend::Exhausted => {
+                error!(
+                    "burst position {} not confirmed after {} attempts, give up",
+                    position, attempts.sent
+                );
+                in_flight.remove(&position);
+                return;
+            }
+        }
+        // the primary endpoint may change between the attempts
+        let rpc = ctx.rpc();
+        let (blockhash, last_valid_block_height) =
+            match rpc.get_latest_blockhash_with_commitment(commitment).await {
+                Ok(v) => v,
+                Err(e) => {
+                    error!("get latest blockhash error:{}", e);
+                    attempts.blockhash_failed();
+                    time::sleep(CONFIRM_POLL_INTERVAL).await;
+                    continue;
+                }
+            };
+        let budget = get_compute_budget(&fee, &ctx, &keys, max_unit_price).await;
+        let tx = client::burst_position(&ctx, budget, blockhash, &keys);
+        let signature = tx.signatures[0];
+        let attempt = attempts.send();
+        if let Some(mut v) = in_flight.get_mut(&position) {
+            v.attempts = attempt;
+            v.signatures.push(signature);
+            v.updated_at = Instant::now();
+        }
+        if let Err(e) = ctx.endpoints.send_transaction(&tx).await {
+            if e.get_transaction_error().is_some() {
+                error!("burst position {} send error:{}", position, e);
+                in_flight.remove(&position);
+                return;
+            }
+            // the endpoint failed, try again on the next primary endpoint
+            warn!("burst position {} send error:{}, retry", position, e);
+            time::sleep(CONFIRM_POLL_INTERVAL).await;
+            continue;
+        }
+        debug!(
+            "burst position {} sent, attempt: {},tx: {}",
+            position, attempt, signature
+        );
+        loop {
+            time::sleep(CONFIRM_POLL_INTERVAL).await;
+            match rpc.get_signature_statuses(&[signature]).await {
+                Ok(rs) => match rs.value.get(0).cloned().flatten() {
+                    Some(status) => {
+                        if let Some(e) = status.err {
+                            error!("burst position {} failed:{},tx: {}", position, e, signature);
+                            in_flight.remove(&position);
+                            return;
+                        }
+                        if status.satisfies_commitment(commitment) {
+                            info!(
+                                "burst position success! pubkey: {},tx: {}",
+                                position, signature
+                            );
+                            if let Some(mut v) = in_flight.get_mut(&position) {
+                                v.status = BurstStatus::Confirmed;
+                                v.updated_at = Instant::now();
+                            }
+                            return;
+                        }
+                    }
+                    None => {}
+                },
+                Err(e) => {
+                    debug!("get signature status error:{}", e);
+                }
+            }
+            match rpc.get_block_height().await {
+                Ok(h) if is_expired(h, last_valid_block_height) => {
+                    warn!(
+                        "burst position {} expired, rebroadcast with a fresh blockhash,tx: {}",
+                        position, signature
+                    );
+                    break;
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    debug!("get block height error:{}", e);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::endpoint;
+    use anchor_client::solana_sdk::{commitment_config::CommitmentConfig, signature::Keypair};
+
+    fn submitter() -> Submitter {
+        let config = config::Config::default();
+        let commitment = CommitmentConfig::confirmed();
+        let ctx = com::BotContext {
+            endpoints: Arc::new(endpoint::EndpointPool::new(&config, commitment)),
+            payer: Keypair::new(),
+            commitment,
+        };
+        Submitter::new(config, Arc::new(ctx))
+    }
+
+    fn keys() -> client::BurstAccounts {
+        client::BurstAccounts {
+            user_account: Pubkey::new_unique(),
+            market_account: Pubkey::new_unique(),
+            position_account: Pubkey::new_unique(),
+            pyth_price_account: Pubkey::new_unique(),
+            chianlink_price_account: Pubkey::new_unique(),
+        }
+    }
+
+    #[test]
+    fn position_is_registered_once() {
+        let s = submitter();
+        let k = keys();
+        assert!(s.register(&k));
+        assert!(s.is_pending(&k.position_account));
+        assert!(!s.register(&k));
+        assert!(s.register(&keys()));
+        assert_eq!(s.in_flight_len(), 2);
+    }
+
+    #[test]
+    fn user_is_pending_until_every_burst_is_confirmed() {
+        let s = submitter();
+        let a = keys();
+        let b = client::BurstAccounts {
+            position_account: Pubkey::new_unique(),
+            ..a
+        };
+        assert_eq!(s.user_pending(&a.user_account), None);
+        assert!(s.register(&a));
+        assert!(s.register(&b));
+        if let Some(mut v) = s.in_flight.get_mut(&a.position_account) {
+            v.status = BurstStatus::Confirmed;
+        }
+        assert_eq!(s.user_pending(&a.user_account), Some(BurstStatus::InFlight));
+        if let Some(mut v) = s.in_flight.get_mut(&b.position_account) {
+            v.status = BurstStatus::Confirmed;
+        }
+        assert_eq!(
+            s.user_pending(&a.user_account),
+            Some(BurstStatus::Confirmed)
+        );
+        assert_eq!(s.user_pending(&Pubkey::new_unique()), None);
+    }
+
+    #[test]
+    fn confirmed_position_waits_for_the_cooldown() {
+        let s = submitter();
+        let k = keys();
+        assert!(s.register(&k));
+        if let Some(mut v) = s.in_flight.get_mut(&k.position_account) {
+            v.status = BurstStatus::Confirmed;
+        }
+        assert_eq!(s.in_flight_len(), 0);
+        assert!(s.is_pending(&k.position_account));
+        assert!(!s.register(&k));
+
+        if let Some(mut v) = s.in_flight.get_mut(&k.position_account) {
+            v.updated_at = Instant::now() - CONFIRMED_COOLDOWN - Duration::from_secs(1);
+        }
+        assert!(!s.is_pending(&k.position_account));
+        assert!(s.register(&k));
+        assert_eq!(s.in_flight_len(), 1);
+    }
+
+    #[tokio::test]
+    async fn nothing_is_submitted_after_shutdown() {
+        let s = submitter();
+        s.shutdown();
+        let k = keys();
+        assert!(!s.submit(k, 0));
+        assert!(!s.is_pending(&k.position_account));
+        assert_eq!(Attempts::default().next(true), Resend::Stopping);
+    }
+
+    #[test]
+    fn expired_blockhash_is_sent_again_until_the_attempts_run_out() {
+        assert!(!is_expired(100, 100));
+        assert!(is_expired(101, 100));
+
+        let mut a = Attempts::default();
+        for attempt in 1..=MAX_SEND_ATTEMPTS {
+            assert_eq!(a.next(false), Resend::Send);
+            assert_eq!(a.send(), attempt);
+        }
+        assert_eq!(a.next(false), Resend::Exhausted);
+    }
+
+    #[test]
+    fn blockhash_errors_do_not_use_up_attempts() {
+        let mut a = Attempts::default();
+        a.send();
+        for _ in 0..MAX_BLOCKHASH_ERRORS - 1 {
+            a.blockhash_failed();
+            assert_eq!(a.next(false), Resend::Send);
+        }
+        assert_eq!(a.sent, 1);
+        // a fetched blockhash resets the errors in a row
+        a.send();
+        a.blockhash_failed();
+        assert_eq!(a.next(false), Resend::Send);
+        for _ in 1..MAX_BLOCKHASH_ERRORS {
+            a.blockhash_failed();
+        }
+        assert_eq!(a.next(false), Resend::Exhausted);
+        assert_eq!(a.sent, 2);
+    }
+}
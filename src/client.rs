@@ -1,12 +1,26 @@
 use crate::com;
+use anchor_client::anchor_lang::{InstructionData, ToAccountMetas};
+use anchor_client::solana_sdk::compute_budget::ComputeBudgetInstruction;
+use anchor_client::solana_sdk::hash::Hash;
+use anchor_client::solana_sdk::instruction::Instruction;
 use anchor_client::solana_sdk::pubkey::Pubkey;
+use anchor_client::solana_sdk::signature::Signer;
 use anchor_client::solana_sdk::system_program;
+use anchor_client::solana_sdk::transaction::Transaction;
 use anchor_client::ClientError;
 use bond::state::{market, position, user};
 use bond::{accounts, com as bcom, instruction};
 use log::debug;
+use serde::Deserialize;
+use serde_json::json;
+use solana_client::rpc_request::RpcRequest;
 use spl_associated_token_account;
 use spl_token;
+// The identity argument of ClosePosition tells the program who closes the position, and so
+// whether it ends up NormalClosing or ForceClosing. The owner closes with 1, as the cli always
+// has; the robot closes other users' positions as a third party with 2.
+const OWNER_CLOSE_IDENTITY: u8 = 1;
+const ROBOT_CLOSE_IDENTITY: u8 = 2;
 pub fn init_vault(ctx: com::Context) -> anyhow::Result<()> {
     let program = ctx.client.program(com::id());
 
@@ -321,7 +335,9 @@ pub fn close_position(ctx: com::Context, args: &clap::ArgMatches) -> anyhow::Res
             user_account,
             position_account,
         })
-        .args(instruction::ClosePosition { identity: 1 })
+        .args(instruction::ClosePosition {
+            identity: OWNER_CLOSE_IDENTITY,
+        })
         .send()
         .map_err(|e| debug_rpc_error(e))?;
 
@@ -371,29 +387,95 @@ use_account: {:#?}"#,
     );
     Ok(())
 }
+// Compute budget of a transaction, the unit price is in micro-lamports.
+#[derive(Debug, Clone, Copy)]
+pub struct ComputeBudget {
+    pub unit_limit: u32,
+    pub unit_price: u64,
+}
+
+impl ComputeBudget {
+    pub fn instructions(&self) -> Vec<Instruction> {
+        let mut ixs = vec![ComputeBudgetInstruction::set_compute_unit_limit(
+            self.unit_limit,
+        )];
+        if self.unit_price > 0 {
+            ixs.push(ComputeBudgetInstruction::set_compute_unit_price(
+                self.unit_price,
+            ));
+        }
+        ixs
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RecentPrioritizationFee {
+    prioritization_fee: u64,
+}
+
+// Get the 75th percentile of the recent prioritization fees paid to write the accounts.
+pub async fn get_recent_prioritization_fee(
+    ctx: &com::BotContext,
+    accounts: &[Pubkey],
+) -> anyhow::Result<u64> {
+    let accounts: Vec<String> = accounts.iter().map(|a| a.to_string()).collect();
+    let rs: Vec<RecentPrioritizationFee> = ctx
+        .rpc()
+        .send(
+            RpcRequest::Custom {
+                method: "getRecentPrioritizationFees",
+            },
+            json!([accounts]),
+        )
+        .await
+        .map_err(|e| com::CliError::SendTransactionError(e.to_string()))?;
+    let mut fees: Vec<u64> = rs.iter().map(|f| f.prioritization_fee).collect();
+    if fees.is_empty() {
+        return Ok(0);
+    }
+    fees.sort_unstable();
+    Ok(fees[(fees.len() - 1) * 3 / 4])
+}
+
+// The accounts of a position closed by the robot.
+#[derive(Debug, Clone, Copy)]
+pub struct BurstAccounts {
+    pub user_account: Pubkey,
+    pub market_account: Pubkey,
+    pub position_account: Pubkey,
+    pub pyth_price_account: Pubkey,
+    pub chianlink_price_account: Pubkey,
+}
+
 pub fn burst_position(
-    client: &anchor_client::Client,
-    user_account: Pubkey,
-    market_account: Pubkey,
-    position_account: Pubkey,
-    pyth_price_account: Pubkey,
-    chianlink_price_account: Pubkey,
-) -> anyhow::Result<()> {
-    let program = client.program(com::id());
-    let tx = program
-        .request()
-        .accounts(accounts::ClosePosition {
-            authority: program.payer(),
-            market_account,
-            pyth_price_account,
-            chianlink_price_account,
-            user_account,
-            position_account,
-        })
-        .send()
-        .map_err(|e| debug_rpc_error(e))?;
-    debug!("burst position success! tx: {}", tx);
-    Ok(())
+    ctx: &com::BotContext,
+    budget: ComputeBudget,
+    blockhash: Hash,
+    keys: &BurstAccounts,
+) -> Transaction {
+    let mut ixs = budget.instructions();
+    ixs.push(burst_instruction(ctx.payer.pubkey(), keys));
+    Transaction::new_signed_with_payer(&ixs, Some(&ctx.payer.pubkey()), &[&ctx.payer], blockhash)
+}
+
+fn burst_instruction(authority: Pubkey, keys: &BurstAccounts) -> Instruction {
+    Instruction {
+        program_id: com::id(),
+        accounts: accounts::ClosePosition {
+            authority,
+            market_account: keys.market_account,
+            pyth_price_account: keys.pyth_price_account,
+            chianlink_price_account: keys.chianlink_price_account,
+            user_account: keys.user_account,
+            position_account: keys.position_account,
+        }
+        .to_account_metas(None),
+        data: instruction::ClosePosition {
+            identity: ROBOT_CLOSE_IDENTITY,
+        }
+        .data(),
+    }
 }
 pub fn investment(ctx: com::Context, args: &clap::ArgMatches) -> anyhow::Result<()> {
     let program = ctx.client.program(com::id());
@@ -515,3 +597,41 @@ fn debug_rpc_error(e: ClientError) -> com::CliError {
     }
     err
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn burst_instruction_closes_as_robot() {
+        let keys = BurstAccounts {
+            user_account: Pubkey::new_unique(),
+            market_account: Pubkey::new_unique(),
+            position_account: Pubkey::new_unique(),
+            pyth_price_account: Pubkey::new_unique(),
+            chianlink_price_account: Pubkey::new_unique(),
+        };
+        let authority = Pubkey::new_unique();
+        let ix = burst_instruction(authority, &keys);
+        assert_eq!(ix.program_id, com::id());
+        // sha256("global:close_position")[..8], then the borsh encoded identity
+        assert_eq!(ix.data, vec![123, 134, 81, 0, 49, 68, 98, 98, 2]);
+        // the meta order follows the bond accounts struct, so only check who is in it
+        let metas: Vec<(Pubkey, bool)> = ix
+            .accounts
+            .iter()
+            .map(|m| (m.pubkey, m.is_signer))
+            .collect();
+        assert_eq!(metas.len(), 6);
+        for meta in [
+            (authority, true),
+            (keys.market_account, false),
+            (keys.pyth_price_account, false),
+            (keys.chianlink_price_account, false),
+            (keys.user_account, false),
+            (keys.position_account, false),
+        ] {
+            assert!(metas.contains(&meta), "missing {:?}", meta);
+        }
+    }
+}
@@ -1,4 +1,5 @@
 use crate::bot::app;
+use crate::bot::storage;
 use crate::client;
 use crate::com;
 use crate::config;
@@ -79,6 +80,14 @@ fn cli() -> Command {
                 .arg(arg!(-t --tasks <TASKS> "The number of settlement tasks that the robot can open, corresponding to the number of tasks in the tokio, 1 by default.").value_parser(clap::value_parser!(usize)))
                 .arg(arg!(-p --port <PORT> "The web server port provides http query service and websocket push service. The default value is 3000. If it is set to 0, the web service is disabled.").value_parser(clap::value_parser!(u64)))
                 .arg(arg!(-i --ip <IP> "The IP address bound to the web server. The default is 127.0.0.1."))
+                .arg(arg!(-c --commitment <COMMITMENT> "The commitment level liquidation transactions are confirmed at. Optional values: processed,confirmed,finalized. The default is confirmed."))
+                .arg(arg!(--"dry-run" "Do everything except sending transactions, the positions that would be burst are logged and recorded in the local db."))
+        )
+        .subcommand(
+            Command::new("db").about("local db of the robot.")
+            .args_conflicts_with_subcommands(true)
+            .subcommand_required(true)
+            .subcommand(Command::new("migrate").about("upgrade the local db to the storage format of this version, stop the robot first."))
         )
 }
 
@@ -161,6 +170,13 @@ pub fn run() -> anyhow::Result<()> {
             let ctx = com::Context::new(&config, &client);
             app::run(ctx, sub_matches)?
         }
+        Some(("db", sub_matches)) => match sub_matches.subcommand() {
+            Some(("migrate", _sub_matches)) => storage::migrate(config)?,
+            Some((name, _)) => {
+                unreachable!("Unsupported subcommand `{}`", name)
+            }
+            None => unreachable!(),
+        },
         Some((ext, sub_matches)) => {
             let args = sub_matches
                 .get_many::<OsString>("")
@@ -1,12 +1,20 @@
 use std::rc::Rc;
+use std::sync::Arc;
 
 use anchor_client::solana_sdk::pubkey::Pubkey;
 use thiserror::Error;
 
-use crate::config;
+use crate::{config, endpoint};
 use anchor_client::solana_sdk::commitment_config::CommitmentConfig;
-use anchor_client::solana_sdk::signature;
+use anchor_client::solana_sdk::signature::{self, Keypair};
+use bond::com as bcom;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use solana_client::nonblocking::rpc_client::RpcClient;
+use std::fmt;
 use std::io::Cursor;
+use std::iter::Sum;
+use std::ops::{Add, AddAssign, Neg, Sub, SubAssign};
+use std::str::FromStr;
 
 #[derive(Error, Debug)]
 pub enum CliError {
@@ -30,7 +38,12 @@ pub enum CliError {
     KeypairError(String),
     #[error("Http server error:{0}")]
     HttpServerError(String),
+    #[error("Send transaction error:{0}")]
+    SendTransactionError(String),
 }
+// the target duration of a slot in milliseconds
+pub const SLOT_MS: u64 = 400;
+
 pub fn id() -> Pubkey {
     Pubkey::try_from("FXUEM9ZfqeWkAtHDCoCGB7C9cwNW1JcyhXB47i9J6B37").unwrap()
 }
@@ -45,9 +58,7 @@ impl<'a> Context<'a> {
     }
 
     pub fn new_client(c: &'a config::Config) -> anyhow::Result<anchor_client::Client> {
-        let mut buff = Cursor::new(c.keypair.clone());
-        let kp = signature::read_keypair(&mut buff)
-            .map_err(|e| CliError::KeypairError(e.to_string()))?;
+        let kp = read_keypair(c)?;
         Ok(anchor_client::Client::new_with_options(
             c.cluster.clone(),
             Rc::new(kp),
@@ -55,6 +66,245 @@ impl<'a> Context<'a> {
         ))
     }
 }
+// The rpc endpoints and signer shared by all bot tasks.
+pub struct BotContext {
+    pub endpoints: endpoint::SharedEndpointPool,
+    pub payer: Keypair,
+    // the level transactions sent by the bot are confirmed at
+    pub commitment: CommitmentConfig,
+}
+pub type SharedBotContext = Arc<BotContext>;
+
+impl BotContext {
+    pub fn new(c: &config::Config, commitment: CommitmentConfig) -> anyhow::Result<Self> {
+        let payer = read_keypair(c)?;
+        let endpoints = Arc::new(endpoint::EndpointPool::new(c, commitment));
+        Ok(Self {
+            endpoints,
+            payer,
+            commitment,
+        })
+    }
+
+    // The rpc client of the primary endpoint.
+    pub fn rpc(&self) -> Arc<RpcClient> {
+        self.endpoints.rpc()
+    }
+}
+
+fn read_keypair(c: &config::Config) -> anyhow::Result<Keypair> {
+    let mut buff = Cursor::new(c.keypair.clone());
+    let kp =
+        signature::read_keypair(&mut buff).map_err(|e| CliError::KeypairError(e.to_string()))?;
+    Ok(kp)
+}
 pub fn f64_round(f: f64) -> f64 {
     (f * 100.0).round() / 100.0
 }
+
+// An amount in the integer base units of the program, i.e. the quote value times DECIMALS.
+// The accounts store amounts as f64 base units, they are rounded to the nearest unit once
+// when read so that sums and thresholds are exact.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Money(i64);
+
+impl Money {
+    pub const ZERO: Money = Money(0);
+
+    pub fn from_units(units: i64) -> Self {
+        Self(units)
+    }
+
+    // from the f64 base units of the program
+    pub fn from_chain(v: f64) -> Self {
+        Self(v.round() as i64)
+    }
+
+    pub fn units(self) -> i64 {
+        self.0
+    }
+
+    // Return the f64 base units to pass to the program types.
+    pub fn to_chain(self) -> f64 {
+        self.0 as f64
+    }
+
+    // Return self * rate rounded to the nearest unit.
+    pub fn mul_rate(self, rate: f64) -> Self {
+        Self((self.0 as f64 * rate).round() as i64)
+    }
+
+    // Return self / base, f64::MAX if base is not positive.
+    pub fn rate(self, base: Money) -> f64 {
+        if base.0 <= 0 {
+            return f64::MAX;
+        }
+        self.0 as f64 / base.0 as f64
+    }
+
+    // Return true if self / base < rate, compared in base units.
+    pub fn is_below_rate(self, base: Money, rate: f64) -> bool {
+        base.0 > 0 && self < base.mul_rate(rate)
+    }
+
+    fn scale() -> u64 {
+        bcom::DECIMALS as u64
+    }
+
+    fn digits() -> usize {
+        let mut n = 0;
+        let mut s = Self::scale();
+        while s >= 10 {
+            s /= 10;
+            n += 1;
+        }
+        n
+    }
+}
+
+impl Add for Money {
+    type Output = Money;
+    fn add(self, rhs: Money) -> Money {
+        Money(self.0 + rhs.0)
+    }
+}
+impl Sub for Money {
+    type Output = Money;
+    fn sub(self, rhs: Money) -> Money {
+        Money(self.0 - rhs.0)
+    }
+}
+impl Neg for Money {
+    type Output = Money;
+    fn neg(self) -> Money {
+        Money(-self.0)
+    }
+}
+impl AddAssign for Money {
+    fn add_assign(&mut self, rhs: Money) {
+        self.0 += rhs.0;
+    }
+}
+impl SubAssign for Money {
+    fn sub_assign(&mut self, rhs: Money) {
+        self.0 -= rhs.0;
+    }
+}
+impl Sum for Money {
+    fn sum<I: Iterator<Item = Money>>(iter: I) -> Money {
+        iter.fold(Money::ZERO, |a, b| a + b)
+    }
+}
+
+// e.g. 12.345678 in the quote currency
+impl fmt::Display for Money {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sign = if self.0 < 0 { "-" } else { "" };
+        let abs = self.0.unsigned_abs();
+        let scale = Self::scale();
+        let digits = Self::digits();
+        if digits == 0 {
+            return write!(f, "{}{}", sign, abs);
+        }
+        write!(
+            f,
+            "{}{}.{:0width$}",
+            sign,
+            abs / scale,
+            abs % scale,
+            width = digits
+        )
+    }
+}
+
+impl FromStr for Money {
+    type Err = CliError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || CliError::Unknown(format!("invalid money: {}", s));
+        let (negative, v) = match s.trim().strip_prefix('-') {
+            Some(v) => (true, v),
+            None => (false, s.trim()),
+        };
+        let (int, frac) = match v.split_once('.') {
+            Some((i, f)) => (i, f),
+            None => (v, ""),
+        };
+        let digits = Self::digits();
+        // the sign is only taken once, in front, so both parts are plain digits
+        let is_digits = |v: &str| v.bytes().all(|b| b.is_ascii_digit());
+        if int.is_empty() || frac.len() > digits || !is_digits(int) || !is_digits(frac) {
+            return Err(err());
+        }
+        let int: i64 = int.parse().map_err(|_| err())?;
+        let frac: i64 = if frac.is_empty() {
+            0
+        } else {
+            let f: i64 = frac.parse().map_err(|_| err())?;
+            f * 10i64.pow((digits - frac.len()) as u32)
+        };
+        let units = int
+            .checked_mul(Self::scale() as i64)
+            .and_then(|v| v.checked_add(frac))
+            .ok_or_else(err)?;
+        Ok(Money(if negative { -units } else { units }))
+    }
+}
+
+// Serialized as the decimal string in the quote currency, or the base units in binary formats.
+impl Serialize for Money {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if !serializer.is_human_readable() {
+            return serializer.serialize_i64(self.0);
+        }
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Money {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if !deserializer.is_human_readable() {
+            return i64::deserialize(deserializer).map(Money);
+        }
+        // Records written before Money was used store f64 base units.
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Str(String),
+            Chain(f64),
+        }
+        match Repr::deserialize(deserializer)? {
+            Repr::Str(s) => Money::from_str(&s).map_err(serde::de::Error::custom),
+            Repr::Chain(v) => Ok(Money::from_chain(v)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn money_parses_what_it_displays() {
+        for i in -1000i64..1000 {
+            let m = Money::from_units(i * 1_000_000_007 + i % 13);
+            assert_eq!(m.to_string().parse::<Money>().unwrap(), m);
+        }
+    }
+
+    #[test]
+    fn money_rejects_a_sign_inside() {
+        for s in [
+            "1.-5", "1.+5", "--1", "-+1", "+1", "1.5-", " 1. 5", "1e3", ".5", "1..5",
+        ] {
+            assert!(s.parse::<Money>().is_err(), "{}", s);
+        }
+        assert_eq!(
+            "-1.5".parse::<Money>().unwrap(),
+            -"1.5".parse::<Money>().unwrap()
+        );
+        assert_eq!(
+            "1.".parse::<Money>().unwrap(),
+            "1".parse::<Money>().unwrap()
+        );
+    }
+}
@@ -25,6 +25,13 @@ pub struct Config {
     pub wallet: PathBuf,
     pub store_path: PathBuf,
     pub accounts: Accounts,
+    pub markets: HashMap<String, MarketConfig>,
+    pub priority_fee: PriorityFeeConfig,
+    pub margin_call: MarginCallConfig,
+    pub account_source: AccountSourceConfig,
+    pub endpoints: EndpointsConfig,
+    pub storage: StorageConfig,
+    pub risk_queue: RiskQueueConfig,
     pub keypair: Vec<u8>,
 }
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -35,6 +42,20 @@ pub struct ConfigBody {
     pub cluster: String,
     pub store_path: String,
     pub accounts: Accounts,
+    #[serde(default)]
+    pub markets: HashMap<String, MarketConfig>,
+    #[serde(default)]
+    pub priority_fee: PriorityFeeConfig,
+    #[serde(default)]
+    pub margin_call: MarginCallConfig,
+    #[serde(default)]
+    pub account_source: AccountSourceConfig,
+    #[serde(default)]
+    pub endpoints: EndpointsConfig,
+    #[serde(default)]
+    pub storage: StorageConfig,
+    #[serde(default)]
+    pub risk_queue: RiskQueueConfig,
 }
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Accounts {
@@ -43,6 +64,163 @@ pub struct Accounts {
     pub spl_mint: Pubkey,
     pub pyth_program_pubkey: Pubkey,
 }
+// Risk settings of a market, keyed by the market pair. e.g. BTC/USD
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MarketConfig {
+    // The price older than this is not trusted.
+    pub max_stale_seconds: i64,
+    // The maximum of confidence interval / price that is trusted.
+    pub max_conf_ratio: f64,
+    // The cap of compute unit price of transactions on this market, in micro-lamports.
+    pub max_compute_unit_price: u64,
+}
+impl Default for MarketConfig {
+    fn default() -> Self {
+        Self {
+            max_stale_seconds: 60,
+            max_conf_ratio: 0.01,
+            max_compute_unit_price: 1_000_000,
+        }
+    }
+}
+// Compute budget of the transactions sent by the bot.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PriorityFeeConfig {
+    pub compute_unit_limit: u32,
+    // in micro-lamports
+    pub compute_unit_price: u64,
+    // Raise the price to the recent prioritization fees of the written accounts.
+    pub dynamic: bool,
+}
+impl Default for PriorityFeeConfig {
+    fn default() -> Self {
+        Self {
+            compute_unit_limit: 200_000,
+            compute_unit_price: 1_000,
+            dynamic: false,
+        }
+    }
+}
+// Margin call warnings sent before a forced close.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MarginCallConfig {
+    // Margin ratios above the burst rate, an event is sent when a position or user falls under one.
+    pub levels: Vec<f64>,
+}
+impl Default for MarginCallConfig {
+    fn default() -> Self {
+        Self {
+            levels: vec![bcom::BURST_RATE * 1.5, bcom::BURST_RATE * 1.2],
+        }
+    }
+}
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AccountSourceKind {
+    // websocket subscriptions of the rpc node
+    Pubsub,
+    // geyser grpc stream, needs the geyser feature
+    Geyser,
+}
+// Where the bot gets the account updates from.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AccountSourceConfig {
+    pub kind: AccountSourceKind,
+    pub geyser_url: String,
+    // sent as the x-token header
+    pub geyser_token: Option<String>,
+    // a websocket or geyser stream without any slot notification or ping for longer is
+    // reconnected, 0 disables
+    pub max_silence_seconds: u64,
+}
+impl Default for AccountSourceConfig {
+    fn default() -> Self {
+        Self {
+            kind: AccountSourceKind::Pubsub,
+            geyser_url: "http://127.0.0.1:10000".to_string(),
+            geyser_token: None,
+            max_silence_seconds: 30,
+        }
+    }
+}
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EndpointConfig {
+    pub rpc_url: String,
+    pub ws_url: String,
+}
+// The rpc and websocket endpoints used besides the ones of the cluster.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EndpointsConfig {
+    pub extra: Vec<EndpointConfig>,
+    pub health_check_seconds: u64,
+    // An endpoint is unhealthy when it is this many slots behind the highest endpoint.
+    pub max_slot_lag: u64,
+    // An endpoint is unhealthy when getSlot takes longer than this, and a send that is not
+    // answered in this time counts as failed.
+    pub max_latency_ms: u64,
+    // Send the burst transactions to all the healthy endpoints at once, the first accepted
+    // send wins.
+    pub broadcast: bool,
+}
+impl Default for EndpointsConfig {
+    fn default() -> Self {
+        Self {
+            extra: Vec::new(),
+            health_check_seconds: 10,
+            max_slot_lag: 30,
+            max_latency_ms: 1500,
+            broadcast: false,
+        }
+    }
+}
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FlushPolicy {
+    // flushed in the background every flush_interval_ms
+    Interval,
+    // flushed after every write, slower but nothing is lost on a crash
+    Every,
+}
+// How the local db is written to disk.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StorageConfig {
+    pub flush: FlushPolicy,
+    pub flush_interval_ms: u64,
+}
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            flush: FlushPolicy::Interval,
+            flush_interval_ms: 500,
+        }
+    }
+}
+// How often the liquidation workers check a user. Users are queued by the full sweep and by
+// the price updates of their markets, a queued user is only checked when it is due for its tier.
+// A far user queued by the sweep is due again after far_interval_seconds, so it must stay below
+// full_sweep_seconds or the far users of a quiet market are only checked every other sweep.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RiskQueueConfig {
+    pub middle_interval_seconds: u64,
+    pub far_interval_seconds: u64,
+    pub full_sweep_seconds: u64,
+}
+impl Default for RiskQueueConfig {
+    fn default() -> Self {
+        Self {
+            middle_interval_seconds: 5,
+            far_interval_seconds: 25,
+            full_sweep_seconds: 30,
+        }
+    }
+}
 impl From<&Config> for ConfigBody {
     fn from(c: &Config) -> Self {
         Self {
@@ -52,6 +230,13 @@ impl From<&Config> for ConfigBody {
             cluster: c.cluster.to_string(),
             store_path: c.store_path.to_str().unwrap().to_string(),
             accounts: c.accounts.clone(),
+            markets: c.markets.clone(),
+            priority_fee: c.priority_fee.clone(),
+            margin_call: c.margin_call.clone(),
+            account_source: c.account_source.clone(),
+            endpoints: c.endpoints.clone(),
+            storage: c.storage.clone(),
+            risk_queue: c.risk_queue.clone(),
         }
     }
 }
@@ -74,6 +259,13 @@ impl From<&ConfigBody> for Config {
             wallet,
             store_path: PathBuf::from(c.store_path.clone()),
             accounts: c.accounts.clone(),
+            markets: c.markets.clone(),
+            priority_fee: c.priority_fee.clone(),
+            margin_call: c.margin_call.clone(),
+            account_source: c.account_source.clone(),
+            endpoints: c.endpoints.clone(),
+            storage: c.storage.clone(),
+            risk_queue: c.risk_queue.clone(),
             keypair,
         }
     }
@@ -127,6 +319,13 @@ impl Default for Config {
                 spl_mint: Pubkey::try_from(SPL_MINT_DEVNET).unwrap(),
                 pyth_program_pubkey: Pubkey::try_from(PYTH_PROGRAM_DEVNET).unwrap(),
             },
+            markets: HashMap::new(),
+            priority_fee: PriorityFeeConfig::default(),
+            margin_call: MarginCallConfig::default(),
+            account_source: AccountSourceConfig::default(),
+            endpoints: EndpointsConfig::default(),
+            storage: StorageConfig::default(),
+            risk_queue: RiskQueueConfig::default(),
             keypair: vec![],
         }
     }
@@ -160,29 +359,37 @@ pyth  program account: {} "#,
             self.accounts.pyth_program_pubkey,
         );
     }
-    pub fn get_pyth_btc_pubkey(&self) -> &Pubkey {
-        let p = self
-            .accounts
-            .pyth
-            .get(&bcom::FullPositionMarket::BtcUsd.to_string())
-            .unwrap();
-        p
+    // Return the margin call levels above the burst rate, from the highest.
+    pub fn get_margin_call_levels(&self) -> Vec<f64> {
+        let mut levels: Vec<f64> = self
+            .margin_call
+            .levels
+            .iter()
+            .copied()
+            .filter(|l| *l > bcom::BURST_RATE)
+            .collect();
+        levels.sort_by(|a, b| b.partial_cmp(a).unwrap_or(std::cmp::Ordering::Equal));
+        levels.dedup();
+        levels
     }
-    pub fn get_pyth_eth_pubkey(&self) -> &Pubkey {
-        let p = self
-            .accounts
-            .pyth
-            .get(&bcom::FullPositionMarket::EthUsd.to_string())
-            .unwrap();
-        p
+    // Return the cluster endpoint first, then the extra ones without duplicates.
+    pub fn get_endpoints(&self) -> Vec<EndpointConfig> {
+        let mut endpoints = vec![EndpointConfig {
+            rpc_url: self.cluster.url().to_string(),
+            ws_url: self.cluster.ws_url().to_string(),
+        }];
+        for e in &self.endpoints.extra {
+            if !endpoints.iter().any(|v| v.rpc_url == e.rpc_url) {
+                endpoints.push(e.clone());
+            }
+        }
+        endpoints
     }
-    pub fn get_pyth_sol_pubkey(&self) -> &Pubkey {
-        let p = self
-            .accounts
-            .pyth
-            .get(&bcom::FullPositionMarket::SolUsd.to_string())
-            .unwrap();
-        p
+    pub fn get_market_config(&self, pair: &str) -> MarketConfig {
+        match self.markets.get(pair) {
+            Some(m) => m.clone(),
+            None => MarketConfig::default(),
+        }
     }
     pub fn set(
         &mut self,
@@ -313,6 +520,13 @@ pyth  program account: {} "#,
         self.cluster = s.cluster;
         self.store_path = s.store_path;
         self.wallet = s.wallet;
+        self.markets = s.markets;
+        self.priority_fee = s.priority_fee;
+        self.margin_call = s.margin_call;
+        self.account_source = s.account_source;
+        self.endpoints = s.endpoints;
+        self.storage = s.storage;
+        self.risk_queue = s.risk_queue;
         self.keypair = s.keypair;
         Ok(())
     }
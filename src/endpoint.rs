@@ -0,0 +1,512 @@
+use crate::{com, config};
+use anchor_client::solana_sdk::commitment_config::CommitmentConfig;
+use anchor_client::solana_sdk::{signature::Signature, transaction::Transaction};
+use chrono::Utc;
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use solana_client::client_error::{ClientError, ClientErrorKind};
+use solana_client::nonblocking::rpc_client::RpcClient;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use tokio::{
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+    time::{self, Duration, Instant},
+};
+
+// The result of the last health check of an endpoint.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EndpointHealth {
+    pub healthy: bool,
+    pub slot: u64,
+    // slots behind the highest endpoint
+    pub slot_lag: u64,
+    pub latency_ms: u64,
+    pub checked_at: i64,
+    pub error: Option<String>,
+}
+
+impl Default for EndpointHealth {
+    // Trusted until the first check.
+    fn default() -> Self {
+        Self {
+            healthy: true,
+            slot: 0,
+            slot_lag: 0,
+            latency_ms: 0,
+            checked_at: 0,
+            error: None,
+        }
+    }
+}
+
+pub struct Endpoint {
+    pub rpc_url: String,
+    pub ws_url: String,
+    pub rpc: Arc<RpcClient>,
+    health: RwLock<EndpointHealth>,
+}
+
+impl Endpoint {
+    pub fn health(&self) -> EndpointHealth {
+        self.health.read().unwrap().clone()
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.health.read().unwrap().healthy
+    }
+}
+
+impl EndpointHealth {
+    // Lower is better, a slot behind weighs as much as the duration of a slot in latency.
+    pub fn score(&self) -> u64 {
+        self.slot_lag
+            .saturating_mul(com::SLOT_MS)
+            .saturating_add(self.latency_ms)
+    }
+}
+
+// The rpc and websocket endpoints of the bot. Requests go to the primary endpoint,
+// which fails over to the healthiest other endpoint when it lags or stops answering.
+pub struct EndpointPool {
+    config: config::EndpointsConfig,
+    endpoints: Vec<Arc<Endpoint>>,
+    primary: AtomicUsize,
+}
+pub type SharedEndpointPool = Arc<EndpointPool>;
+
+impl EndpointPool {
+    // commitment is the default commitment of the rpc clients.
+    pub fn new(config: &config::Config, commitment: CommitmentConfig) -> Self {
+        let endpoints = config
+            .get_endpoints()
+            .into_iter()
+            .map(|e| {
+                Arc::new(Endpoint {
+                    rpc: Arc::new(RpcClient::new_with_commitment(
+                        e.rpc_url.clone(),
+                        commitment,
+                    )),
+                    rpc_url: e.rpc_url,
+                    ws_url: e.ws_url,
+                    health: RwLock::new(EndpointHealth::default()),
+                })
+            })
+            .collect();
+        Self {
+            config: config.endpoints.clone(),
+            endpoints,
+            primary: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn primary(&self) -> Arc<Endpoint> {
+        self.endpoints[self.primary.load(Ordering::Acquire)].clone()
+    }
+
+    pub fn rpc(&self) -> Arc<RpcClient> {
+        self.primary().rpc.clone()
+    }
+
+    pub fn endpoints(&self) -> &[Arc<Endpoint>] {
+        &self.endpoints
+    }
+
+    // Return the healthy endpoints, or the primary one if none is healthy.
+    pub fn healthy(&self) -> Vec<Arc<Endpoint>> {
+        let rs: Vec<Arc<Endpoint>> = self
+            .endpoints
+            .iter()
+            .filter(|e| e.is_healthy())
+            .cloned()
+            .collect();
+        if rs.is_empty() {
+            vec![self.primary()]
+        } else {
+            rs
+        }
+    }
+
+    // Mark an endpoint unhealthy until its next check, e.g. after a failed request.
+    pub fn report_error(&self, url: &str, e: &str) {
+        if let Some(endpoint) = self.endpoints.iter().find(|v| v.rpc_url == url) {
+            let mut h = endpoint.health.write().unwrap();
+            h.healthy = false;
+            h.error = Some(e.to_string());
+        }
+        self.select_primary();
+    }
+
+    // Send a transaction to the primary endpoint, or to all the healthy endpoints if
+    // broadcast is on. Return as soon as an endpoint accepted it, otherwise the last error.
+    // An endpoint that does not answer in max_latency_ms counts as failed.
+    pub async fn send_transaction(&self, tx: &Transaction) -> Result<Signature, ClientError> {
+        let endpoints = if self.config.broadcast {
+            self.healthy()
+        } else {
+            vec![self.primary()]
+        };
+        let timeout = Duration::from_millis(self.config.max_latency_ms);
+        let (rs_tx, mut rs_rx) = mpsc::channel(endpoints.len());
+        for endpoint in endpoints {
+            let tx = tx.clone();
+            let rs_tx = rs_tx.clone();
+            tokio::spawn(async move {
+                let rs = match time::timeout(timeout, endpoint.rpc.send_transaction(&tx)).await {
+                    Ok(rs) => rs,
+                    Err(_) => {
+                        Err(ClientErrorKind::Custom(format!("no answer in {:?}", timeout)).into())
+                    }
+                };
+                let _ = rs_tx.send((endpoint, rs)).await;
+            });
+        }
+        drop(rs_tx);
+        let mut last_error = None;
+        while let Some((endpoint, rs)) = rs_rx.recv().await {
+            match rs {
+                Ok(s) => return Ok(s),
+                Err(e) => {
+                    debug!("send transaction to {} error:{}", endpoint.rpc_url, e);
+                    // a rejected transaction says nothing about the endpoint
+                    if e.get_transaction_error().is_none() {
+                        self.report_error(&endpoint.rpc_url, &e.to_string());
+                    }
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| ClientErrorKind::Custom("no endpoint".to_string()).into()))
+    }
+
+    pub async fn check(&self) {
+        // a slow answer is still recorded, so its latency is known
+        let timeout = Duration::from_millis(self.config.max_latency_ms.saturating_mul(2));
+        let mut tasks = Vec::with_capacity(self.endpoints.len());
+        for endpoint in &self.endpoints {
+            let endpoint = endpoint.clone();
+            tasks.push(tokio::spawn(async move {
+                let start = Instant::now();
+                let rs = time::timeout(
+                    timeout,
+                    endpoint
+                        .rpc
+                        .get_slot_with_commitment(CommitmentConfig::processed()),
+                )
+                .await;
+                let latency = start.elapsed().as_millis() as u64;
+                match rs {
+                    Ok(Ok(slot)) => Ok((slot, latency)),
+                    Ok(Err(e)) => Err(e.to_string()),
+                    Err(_) => Err(format!("no answer in {:?}", timeout)),
+                }
+            }));
+        }
+        let mut rs = Vec::with_capacity(tasks.len());
+        for t in tasks {
+            rs.push(match t.await {
+                Ok(r) => r,
+                Err(e) => Err(e.to_string()),
+            });
+        }
+        self.record(rs);
+    }
+
+    // Record the slot and latency, or the error, of every endpoint in the order of the pool.
+    fn record(&self, rs: Vec<Result<(u64, u64), String>>) {
+        let max_slot = rs
+            .iter()
+            .filter_map(|r| r.as_ref().ok().map(|v| v.0))
+            .max()
+            .unwrap_or(0);
+        let now = Utc::now().timestamp();
+        for (endpoint, r) in self.endpoints.iter().zip(rs) {
+            let mut h = endpoint.health.write().unwrap();
+            let was_healthy = h.healthy;
+            h.checked_at = now;
+            match r {
+                Ok((slot, latency)) => {
+                    h.slot = slot;
+                    h.slot_lag = max_slot - slot;
+                    h.latency_ms = latency;
+                    h.error = if h.slot_lag > self.config.max_slot_lag {
+                        Some(format!("{} slots behind", h.slot_lag))
+                    } else if latency > self.config.max_latency_ms {
+                        Some(format!("answered in {} ms", latency))
+                    } else {
+                        None
+                    };
+                    h.healthy = h.error.is_none();
+                }
+                Err(e) => {
+                    h.healthy = false;
+                    h.error = Some(e);
+                }
+            }
+            if was_healthy != h.healthy {
+                warn!(
+                    "endpoint {} is {}: {:?}",
+                    endpoint.rpc_url,
+                    if h.healthy { "healthy" } else { "unhealthy" },
+                    *h
+                );
+            }
+        }
+        self.select_primary();
+    }
+
+    // Keep the primary endpoint while it is healthy, otherwise switch to the healthy endpoint
+    // with the lowest score of slot lag and latency.
+    fn select_primary(&self) {
+        let current = self.primary.load(Ordering::Acquire);
+        if self.endpoints[current].is_healthy() {
+            return;
+        }
+        let next = self
+            .endpoints
+            .iter()
+            .enumerate()
+            .filter(|(_, e)| e.is_healthy())
+            .min_by_key(|(_, e)| e.health().score())
+            .map(|(i, _)| i);
+        match next {
+            Some(i) => {
+                if self
+                    .primary
+                    .compare_exchange(current, i, Ordering::AcqRel, Ordering::Acquire)
+                    .is_ok()
+                {
+                    warn!(
+                        "failover from endpoint {} to {}",
+                        self.endpoints[current].rpc_url, self.endpoints[i].rpc_url
+                    );
+                }
+            }
+            None => {
+                error!(
+                    "no healthy endpoint, keep using {}",
+                    self.endpoints[current].rpc_url
+                );
+            }
+        }
+    }
+}
+
+// Check the endpoints periodically.
+pub struct EndpointMonitor {
+    shutdown_tx: oneshot::Sender<()>,
+    task: JoinHandle<()>,
+}
+
+impl EndpointMonitor {
+    pub fn new(pool: SharedEndpointPool) -> Self {
+        let (shutdown_tx, mut shutdown_rx) = oneshot::channel::<()>();
+        let period = Duration::from_secs(pool.config.health_check_seconds.max(1));
+        let task = tokio::spawn(async move {
+            info!(
+                "start health check of {} endpoints ...",
+                pool.endpoints.len()
+            );
+            let mut interval = time::interval(period);
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {
+                        pool.check().await;
+                    }
+                    _ = (&mut shutdown_rx) => {
+                        info!("got shutdown signal, endpoint health check exit.");
+                        break;
+                    }
+                }
+            }
+        });
+        Self { shutdown_tx, task }
+    }
+
+    pub async fn shutdown(self) {
+        let _ = self.shutdown_tx.send(());
+        let _ = self.task.await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    fn pool(urls: &[String]) -> EndpointPool {
+        let endpoints = urls
+            .iter()
+            .map(|url| {
+                Arc::new(Endpoint {
+                    rpc: Arc::new(RpcClient::new(url.clone())),
+                    rpc_url: url.clone(),
+                    ws_url: url.replace("http", "ws"),
+                    health: RwLock::new(EndpointHealth::default()),
+                })
+            })
+            .collect();
+        EndpointPool {
+            config: config::EndpointsConfig::default(),
+            endpoints,
+            primary: AtomicUsize::new(0),
+        }
+    }
+
+    fn urls(n: usize) -> Vec<String> {
+        (0..n)
+            .map(|i| format!("http://127.0.0.1:{}", i + 1))
+            .collect()
+    }
+
+    // An rpc node at the slot.
+    async fn serve_slot(slot: u64) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut request = Vec::new();
+                    let mut buf = [0u8; 1024];
+                    loop {
+                        let n = socket.read(&mut buf).await.unwrap_or(0);
+                        if n == 0 {
+                            return;
+                        }
+                        request.extend_from_slice(&buf[..n]);
+                        let text = String::from_utf8_lossy(&request).to_lowercase();
+                        if let Some(end) = text.find("\r\n\r\n") {
+                            let len = text
+                                .lines()
+                                .find_map(|l| l.strip_prefix("content-length:"))
+                                .and_then(|v| v.trim().parse::<usize>().ok())
+                                .unwrap_or(0);
+                            if request.len() >= end + 4 + len {
+                                break;
+                            }
+                        }
+                    }
+                    // the client asks for the version before the first request
+                    let result = if String::from_utf8_lossy(&request).contains("getVersion") {
+                        r#"{"solana-core":"1.10.0","feature-set":0}"#.to_string()
+                    } else {
+                        slot.to_string()
+                    };
+                    let body = format!(r#"{{"jsonrpc":"2.0","result":{},"id":1}}"#, result);
+                    let response = format!(
+                        "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                        body.len(),
+                        body
+                    );
+                    let _ = socket.write_all(response.as_bytes()).await;
+                });
+            }
+        });
+        url
+    }
+
+    // A url nothing listens on.
+    async fn refused() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        format!("http://{}", listener.local_addr().unwrap())
+    }
+
+    #[test]
+    fn score_weighs_a_slot_as_a_slot_of_latency() {
+        let h = |slot_lag, latency_ms| EndpointHealth {
+            slot_lag,
+            latency_ms,
+            ..EndpointHealth::default()
+        };
+        assert_eq!(h(0, 0).score(), 0);
+        assert_eq!(h(2, 100).score(), 2 * com::SLOT_MS + 100);
+        assert!(h(1, 0).score() > h(0, com::SLOT_MS - 1).score());
+        assert_eq!(h(u64::MAX, 1).score(), u64::MAX);
+    }
+
+    #[test]
+    fn record_marks_lagging_and_slow_endpoints() {
+        let p = pool(&urls(4));
+        let max_lag = p.config.max_slot_lag;
+        let max_latency = p.config.max_latency_ms;
+        p.record(vec![
+            Ok((1000, 10)),
+            Ok((1000 - max_lag - 1, 10)),
+            Ok((1000, max_latency + 1)),
+            Err("refused".to_string()),
+        ]);
+        let h: Vec<EndpointHealth> = p.endpoints().iter().map(|e| e.health()).collect();
+        assert!(h[0].healthy && h[0].error.is_none());
+        assert_eq!(h[1].slot_lag, max_lag + 1);
+        assert!(!h[1].healthy);
+        assert!(!h[2].healthy);
+        assert_eq!(h[2].latency_ms, max_latency + 1);
+        assert!(!h[3].healthy);
+        assert_eq!(h[3].error.as_deref(), Some("refused"));
+        // recovers on the next good check
+        p.record(vec![
+            Ok((1001, 10)),
+            Ok((1001, 10)),
+            Ok((1001, 10)),
+            Ok((1001, 10)),
+        ]);
+        assert!(p.endpoints().iter().all(|e| e.is_healthy()));
+    }
+
+    #[test]
+    fn primary_is_kept_while_healthy() {
+        let p = pool(&urls(3));
+        // the primary is not the best one but still healthy
+        p.record(vec![Ok((100, 500)), Ok((100, 10)), Ok((100, 20))]);
+        assert_eq!(p.primary().rpc_url, p.endpoints()[0].rpc_url);
+    }
+
+    #[test]
+    fn primary_fails_over_to_the_lowest_score() {
+        let p = pool(&urls(3));
+        p.record(vec![
+            Err("refused".to_string()),
+            Ok((99, 10)),
+            Ok((100, 200)),
+        ]);
+        // one slot behind costs more than 190 ms
+        assert_eq!(p.primary().rpc_url, p.endpoints()[2].rpc_url);
+        p.report_error(&p.endpoints()[2].rpc_url.clone(), "timeout");
+        assert_eq!(p.primary().rpc_url, p.endpoints()[1].rpc_url);
+    }
+
+    #[test]
+    fn primary_is_kept_when_none_is_healthy() {
+        let p = pool(&urls(2));
+        p.record(vec![Err("refused".to_string()), Err("refused".to_string())]);
+        assert_eq!(p.primary().rpc_url, p.endpoints()[0].rpc_url);
+        assert_eq!(p.healthy().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn check_fails_over_from_an_unreachable_endpoint() {
+        let p = pool(&[refused().await, serve_slot(42).await]);
+        p.check().await;
+        let h = p.endpoints()[1].health();
+        assert!(h.healthy, "{:?}", h);
+        assert_eq!(h.slot, 42);
+        assert!(h.checked_at > 0);
+        assert!(!p.endpoints()[0].is_healthy());
+        assert_eq!(p.primary().rpc_url, p.endpoints()[1].rpc_url);
+    }
+
+    #[tokio::test]
+    async fn send_times_out_without_broadcast() {
+        // connections are queued but never answered
+        let silent = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut p = pool(&[format!("http://{}", silent.local_addr().unwrap())]);
+        p.config.broadcast = false;
+        p.config.max_latency_ms = 50;
+        let start = Instant::now();
+        let rs = p.send_transaction(&Transaction::default()).await;
+        assert!(rs.unwrap_err().to_string().contains("no answer"));
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(!p.primary().is_healthy());
+    }
+}
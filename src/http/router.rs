@@ -1,8 +1,8 @@
 use std::net::SocketAddr;
 
-use log::info;
+use log::{debug, info};
 
-use crate::bot;
+use crate::bot::{self, risk};
 use axum::{
     self,
     error_handling::HandleErrorLayer,
@@ -17,7 +17,7 @@ use axum::{
 };
 use serde::{Deserialize, Serialize};
 use std::{borrow::Cow, time::Duration};
-use tokio::sync::oneshot;
+use tokio::sync::{broadcast, oneshot};
 use tower::{BoxError, ServiceBuilder};
 use tower_http::trace::{DefaultMakeSpan, TraceLayer};
 
@@ -58,6 +58,9 @@ pub fn router(mp: bot::machine::SharedStateMap) -> Router {
             "/user/positions/:prefix/:pubkey",
             get(get_user_position_list),
         )
+        .route("/user/funding/:pubkey", get(get_user_funding_list))
+        .route("/debug/slot/:pubkey", get(get_account_slot))
+        .route("/debug/channels", get(get_channels))
         .route("/ws", get(ws_handler))
         .layer(
             ServiceBuilder::new()
@@ -118,6 +121,52 @@ async fn get_user_position_list(
     Json(rs)
 }
 
+async fn get_user_funding_list(
+    Path(pubkey): Path<String>,
+    Extension(state): Extension<bot::machine::SharedStateMap>,
+) -> impl IntoResponse {
+    let rs = match service::get_funding_list(state, pubkey) {
+        Ok(r) => {
+            let mut j: JsonResponse<Vec<bot::machine::FundingRecord>> = JsonResponse::default();
+            j.data = r;
+            j
+        }
+        Err(e) => {
+            let mut j: JsonResponse<Vec<bot::machine::FundingRecord>> = JsonResponse::default();
+            j.message = e.to_string();
+            j
+        }
+    };
+    Json(rs)
+}
+
+async fn get_account_slot(
+    Path(pubkey): Path<String>,
+    Extension(state): Extension<bot::machine::SharedStateMap>,
+) -> impl IntoResponse {
+    let rs = match service::get_account_slot(state, pubkey) {
+        Ok(r) => {
+            let mut j: JsonResponse<Option<service::AccountSlot>> = JsonResponse::default();
+            j.data = Some(r);
+            j
+        }
+        Err(e) => {
+            let mut j: JsonResponse<Option<service::AccountSlot>> = JsonResponse::default();
+            j.message = e.to_string();
+            j
+        }
+    };
+    Json(rs)
+}
+
+async fn get_channels(
+    Extension(state): Extension<bot::machine::SharedStateMap>,
+) -> impl IntoResponse {
+    let mut j: JsonResponse<Vec<bot::channel::ChannelSnapshot>> = JsonResponse::default();
+    j.data = service::get_channels(state);
+    Json(j)
+}
+
 async fn handle_error(error: BoxError) -> impl IntoResponse {
     if error.is::<tower::timeout::error::Elapsed>() {
         return (StatusCode::REQUEST_TIMEOUT, Cow::from("request timed out"));
@@ -135,52 +184,55 @@ async fn handle_error(error: BoxError) -> impl IntoResponse {
         Cow::from(format!("Unhandled internal error: {}", error)),
     )
 }
+// Stream the margin calls to the client as json text messages.
 async fn ws_handler(
     ws: WebSocketUpgrade,
     user_agent: Option<TypedHeader<headers::UserAgent>>,
+    Extension(state): Extension<bot::machine::SharedStateMap>,
 ) -> impl IntoResponse {
     if let Some(TypedHeader(user_agent)) = user_agent {
-        println!("`{}` connected", user_agent.as_str());
+        info!("`{}` connected", user_agent.as_str());
     }
-
-    ws.on_upgrade(handle_socket)
+    let margin_call_rx = state.margin_call_tx.subscribe();
+    ws.on_upgrade(move |socket| handle_socket(socket, margin_call_rx))
 }
 
-async fn handle_socket(mut socket: WebSocket) {
-    if let Some(msg) = socket.recv().await {
-        if let Ok(msg) = msg {
-            match msg {
-                Message::Text(t) => {
-                    println!("client sent str: {:?}", t);
-                }
-                Message::Binary(_) => {
-                    println!("client sent binary data");
-                }
-                Message::Ping(_) => {
-                    println!("socket ping");
-                }
-                Message::Pong(_) => {
-                    println!("socket pong");
+async fn handle_socket(
+    mut socket: WebSocket,
+    mut margin_call_rx: broadcast::Receiver<risk::MarginCall>,
+) {
+    loop {
+        tokio::select! {
+            msg = socket.recv() => {
+                match msg {
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => {
+                        debug!("ws client disconnected");
+                        return;
+                    }
+                    Some(Ok(_)) => {}
                 }
-                Message::Close(_) => {
-                    println!("client disconnected");
+            }
+            call = margin_call_rx.recv() => {
+                let call = match call {
+                    Ok(c) => c,
+                    Err(broadcast::error::RecvError::Lagged(n)) => {
+                        info!("ws client lagged, {} margin calls dropped", n);
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return,
+                };
+                let text = match serde_json::to_string(&call) {
+                    Ok(t) => t,
+                    Err(e) => {
+                        debug!("margin call serialize error: {}", e);
+                        continue;
+                    }
+                };
+                if socket.send(Message::Text(text)).await.is_err() {
+                    debug!("ws client disconnected");
                     return;
                 }
             }
-        } else {
-            println!("client disconnected");
-            return;
-        }
-    }
-
-    loop {
-        if socket
-            .send(Message::Text(String::from("Hi!")))
-            .await
-            .is_err()
-        {
-            println!("client disconnected");
-            return;
         }
     }
 }
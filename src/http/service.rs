@@ -1,9 +1,9 @@
 use crate::bot::{
     self,
-    machine::{PositionDynamicData, UserDynamicData},
+    risk::{PositionDynamicData, UserDynamicData},
 };
-use crate::bot::{machine, storage};
-use crate::com::CliError;
+use crate::bot::{channel, machine, storage};
+use crate::com::{CliError, Money};
 use anchor_client::solana_sdk::{account::Account, pubkey::Pubkey};
 use bond::com as bcom;
 use log::*;
@@ -12,15 +12,92 @@ use bond::state::{position, user};
 use serde::{Deserialize, Serialize};
 use std::convert::TryFrom;
 use std::str::FromStr;
+
+// user::UserAccount with the amounts as Money, as in the dynamic data.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserAccountData {
+    pub authority: Pubkey,
+    pub balance: Money,
+    pub margin_total: Money,
+    pub margin_full_buy_total: Money,
+    pub margin_full_sell_total: Money,
+    pub margin_full_total: Money,
+    pub margin_independent_buy_total: Money,
+    pub margin_independent_sell_total: Money,
+    pub margin_independent_total: Money,
+    pub position_seed_offset: u64,
+}
+
+impl From<&user::UserAccount> for UserAccountData {
+    fn from(u: &user::UserAccount) -> Self {
+        Self {
+            authority: u.authority,
+            balance: Money::from_chain(u.balance),
+            margin_total: Money::from_chain(u.margin_total),
+            margin_full_buy_total: Money::from_chain(u.margin_full_buy_total),
+            margin_full_sell_total: Money::from_chain(u.margin_full_sell_total),
+            margin_full_total: Money::from_chain(u.margin_full_total),
+            margin_independent_buy_total: Money::from_chain(f64::from(
+                u.margin_independent_buy_total,
+            )),
+            margin_independent_sell_total: Money::from_chain(f64::from(
+                u.margin_independent_sell_total,
+            )),
+            margin_independent_total: Money::from_chain(f64::from(u.margin_independent_total)),
+            position_seed_offset: u64::from(u.position_seed_offset),
+        }
+    }
+}
+
+// position::Position with the prices and amounts as Money.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PositionData {
+    pub authority: Pubkey,
+    pub market_account: Pubkey,
+    pub position_seed_offset: u64,
+    pub position_type: position::PositionType,
+    pub direction: position::Direction,
+    pub position_status: position::PositionStatus,
+    pub size: f64,
+    pub leverage: u16,
+    pub margin: Money,
+    pub open_price: Money,
+    pub open_real_price: Money,
+    pub close_price: Money,
+    pub close_real_price: Money,
+    pub profit: Money,
+}
+
+impl From<&position::Position> for PositionData {
+    fn from(p: &position::Position) -> Self {
+        Self {
+            authority: p.authority,
+            market_account: p.market_account,
+            position_seed_offset: u64::from(p.position_seed_offset),
+            position_type: p.position_type.clone(),
+            direction: p.direction.clone(),
+            position_status: p.position_status.clone(),
+            size: p.size,
+            leverage: p.leverage,
+            margin: Money::from_chain(p.margin),
+            open_price: Money::from_chain(p.open_price),
+            open_real_price: Money::from_chain(p.open_real_price),
+            close_price: Money::from_chain(p.close_price),
+            close_real_price: Money::from_chain(p.close_real_price),
+            profit: Money::from_chain(p.profit),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UserInfo {
-    pub account: user::UserAccount,
+    pub account: UserAccountData,
     pub pubkey: Pubkey,
     pub dynamic_data: Option<UserDynamicData>,
 }
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PositionInfo {
-    pub account: position::Position,
+    pub account: PositionData,
     pub pubkey: Pubkey,
     pub dynamic_data: Option<PositionDynamicData>,
 }
@@ -35,34 +112,17 @@ pub fn get_user_info(
         Some(user) => {
             let data = match mp.user_dynamic_idx.get(&pubkey) {
                 Some(d) => {
-                    let mut dynamic_data = machine::UserDynamicData::default();
-                    dynamic_data.equity = bcom::f64_round(d.value().equity / bcom::DECIMALS);
+                    let mut dynamic_data = UserDynamicData::default();
+                    dynamic_data.equity = d.value().equity;
                     dynamic_data.margin_percentage = bcom::f64_round(d.value().margin_percentage);
-                    dynamic_data.profit = bcom::f64_round(d.value().profit / bcom::DECIMALS);
+                    dynamic_data.profit = d.value().profit;
                     dynamic_data.profit_rate = bcom::f64_round(d.value().profit_rate);
                     Some(dynamic_data)
                 }
                 None => None,
             };
-            let mut user_account = (*user.value()).clone();
-            user_account.margin_total = bcom::f64_round(user_account.margin_total / bcom::DECIMALS);
-            user_account.balance = bcom::f64_round(user_account.balance / bcom::DECIMALS);
-            user_account.margin_full_buy_total =
-                bcom::f64_round(user_account.margin_full_buy_total / bcom::DECIMALS);
-            user_account.margin_full_sell_total =
-                bcom::f64_round(user_account.margin_full_sell_total / bcom::DECIMALS);
-            user_account.margin_full_total =
-                bcom::f64_round(user_account.margin_full_total / bcom::DECIMALS);
-            user_account.margin_independent_buy_total = bcom::f64_round(
-                f64::from(user_account.margin_independent_buy_total) / bcom::DECIMALS,
-            );
-            user_account.margin_independent_sell_total = bcom::f64_round(
-                f64::from(user_account.margin_independent_sell_total) / bcom::DECIMALS,
-            );
-            user_account.margin_independent_total =
-                bcom::f64_round(f64::from(user_account.margin_independent_total) / bcom::DECIMALS);
             let user_info = UserInfo {
-                account: user_account,
+                account: user.value().into(),
                 dynamic_data: data,
                 pubkey,
             };
@@ -88,25 +148,13 @@ pub fn get_position_list(
             match r {
                 Some(p) => {
                     for v in p.value() {
-                        let mut p = (*v.value()).clone();
-                        p.open_price = bcom::f64_round(p.open_price / bcom::DECIMALS);
-                        p.open_real_price = bcom::f64_round(p.open_real_price / bcom::DECIMALS);
-                        if p.position_status == position::PositionStatus::ForceClosing
-                            || p.position_status == position::PositionStatus::NormalClosing
-                        {
-                            p.close_price = bcom::f64_round(p.close_price / bcom::DECIMALS);
-                            p.close_real_price =
-                                bcom::f64_round(p.close_real_price / bcom::DECIMALS);
-                        }
-                        p.profit = bcom::f64_round(p.profit / bcom::DECIMALS);
-                        p.margin = bcom::f64_round(p.margin / bcom::DECIMALS);
                         let data = mp.position_dynamic_idx.get(v.key()).map(|d| {
-                            let mut dynamic_data = machine::PositionDynamicData::default();
+                            let mut dynamic_data = PositionDynamicData::default();
                             dynamic_data.profit_rate = bcom::f64_round(d.value().profit_rate);
                             dynamic_data
                         });
                         rs.push(PositionInfo {
-                            account: p,
+                            account: v.value().into(),
                             pubkey: *v.key(),
                             dynamic_data: data,
                         });
@@ -126,18 +174,17 @@ pub fn get_position_list(
                         let pk = keys.get_end();
                         let pbk = Pubkey::try_from(pk.as_str())
                             .map_err(|e| CliError::Unknown(e.to_string()))?;
-                        let values: Account = serde_json::from_slice(v.to_vec().as_slice())
-                            .map_err(|e| CliError::JsonError(e.to_string()))?;
+                        let values: Account = storage::decode(&v)?;
                         let s: machine::State = (&values).into();
                         let data = mp.position_dynamic_idx.get(&pbk).map(|d| {
-                            let mut dynamic_data = machine::PositionDynamicData::default();
+                            let mut dynamic_data = PositionDynamicData::default();
                             dynamic_data.profit_rate = bcom::f64_round(d.value().profit_rate);
                             dynamic_data
                         });
                         match s {
                             machine::State::Position(m) => {
                                 rs.push(PositionInfo {
-                                    account: m,
+                                    account: (&m).into(),
                                     pubkey: pbk,
                                     dynamic_data: data,
                                 });
@@ -151,7 +198,98 @@ pub fn get_position_list(
                 }
             }
         }
-        storage::Prefix::None => {}
+        storage::Prefix::Funding | storage::Prefix::DryRun | storage::Prefix::None => {}
     }
     Ok(rs)
 }
+
+pub fn get_funding_list(
+    mp: machine::SharedStateMap,
+    pubkey: String,
+) -> anyhow::Result<Vec<machine::FundingRecord>> {
+    let pubkey =
+        Pubkey::try_from(pubkey.as_str()).map_err(|e| CliError::HttpServerError(e.to_string()))?;
+    let mut rs: Vec<machine::FundingRecord> = Vec::new();
+    for i in mp.storage.get_funding_record_list(&pubkey) {
+        match i {
+            Ok((_k, v)) => {
+                let record: machine::FundingRecord = storage::decode(&v)?;
+                rs.push(record);
+            }
+            Err(e) => {
+                error!("{}", e);
+            }
+        }
+    }
+    Ok(rs)
+}
+
+// The last applied and the saved context slot of an account.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AccountSlot {
+    pub pubkey: Pubkey,
+    pub slot: Option<u64>,
+    pub stored_slot: Option<u64>,
+}
+
+pub fn get_account_slot(
+    mp: machine::SharedStateMap,
+    pubkey: String,
+) -> anyhow::Result<AccountSlot> {
+    let pubkey =
+        Pubkey::try_from(pubkey.as_str()).map_err(|e| CliError::HttpServerError(e.to_string()))?;
+    let slot = mp.slot_idx.get(&pubkey).map(|s| *s.value());
+    let stored_slot = mp.storage.get_slot(&pubkey)?;
+    Ok(AccountSlot {
+        pubkey,
+        slot,
+        stored_slot,
+    })
+}
+
+pub fn get_channels(mp: machine::SharedStateMap) -> Vec<channel::ChannelSnapshot> {
+    channel::snapshot(&mp.channels)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anchor_client::anchor_lang::{AccountDeserialize, Discriminator};
+
+    // An account of the program type with every field zero.
+    fn zeroed<T: AccountDeserialize + Discriminator>(len: usize) -> T {
+        let mut data = vec![0u8; 8 + len];
+        data[..8].copy_from_slice(&T::discriminator());
+        T::try_deserialize(&mut &data[..]).unwrap()
+    }
+
+    #[test]
+    fn amounts_are_money_decimal_strings() {
+        let mut u: user::UserAccount = zeroed(user::UserAccount::LEN);
+        u.balance = 1_234_567.0;
+        let v = serde_json::to_value(UserAccountData::from(&u)).unwrap();
+        assert_eq!(
+            v["balance"],
+            serde_json::json!(Money::from_units(1_234_567).to_string())
+        );
+        assert_eq!(
+            v["margin_total"],
+            serde_json::json!(Money::ZERO.to_string())
+        );
+
+        let mut p: position::Position = zeroed(position::Position::LEN);
+        p.margin = 42.0;
+        p.profit = -7.0;
+        p.leverage = 10;
+        let v = serde_json::to_value(PositionData::from(&p)).unwrap();
+        assert_eq!(
+            v["margin"],
+            serde_json::json!(Money::from_units(42).to_string())
+        );
+        assert_eq!(
+            v["profit"],
+            serde_json::json!(Money::from_units(-7).to_string())
+        );
+        assert_eq!(v["leverage"], serde_json::json!(10));
+    }
+}
@@ -3,4 +3,5 @@ pub mod client;
 pub mod cmd;
 pub mod com;
 pub mod config;
+pub mod endpoint;
 pub mod http;